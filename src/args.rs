@@ -1,7 +1,19 @@
-use clap::Parser;
+use std::{
+    ffi::OsString,
+    io::IsTerminal,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use clap::{ArgGroup, Parser};
 use regex::Regex;
 
-#[derive(Copy, Clone, Debug)]
+use crate::{
+    rename::{parse_transform, Transform},
+    template::{Segment, TemplateParser},
+};
+
+#[derive(Copy, Clone, Debug, Default)]
 pub enum SortMode {
     /// Sort by created date
     Created,
@@ -10,52 +22,627 @@ pub enum SortMode {
     Modified,
 
     /// Sort by path (default)
+    #[default]
     Path,
+
+    /// Sort by Finder tags (macOS only), with untagged files last
+    Tag,
+
+    /// Keep the order in which paths were given, and in which directories were walked
+    AsGiven,
+}
+
+/// The order in which files are discovered while walking a directory
+#[derive(Copy, Clone, Debug, Default, clap::ValueEnum)]
+pub enum WalkOrder {
+    /// Files in subdirectories come before the files of their parent (default)
+    #[default]
+    ContentsFirst,
+
+    /// Files in a directory come before the files of its subdirectories
+    ParentsFirst,
+}
+
+/// The order in which operations are applied
+#[derive(Copy, Clone, Debug, Default, clap::ValueEnum)]
+pub enum StrategyMode {
+    /// Try forward order, then reverse order if forward would conflict (default)
+    #[default]
+    Auto,
+
+    /// Apply operations first to last
+    Forward,
+
+    /// Apply operations last to first
+    Reverse,
+
+    /// Move every file to a temporary name first, then to its target; slower, but handles any permutation, e.g. swaps
+    Stage,
+}
+
+/// What to do with a file when the pattern selects an empty name
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum OnEmpty {
+    /// Stop with an error (default)
+    #[default]
+    Error,
+
+    /// Use the original file stem instead
+    KeepOriginal,
+
+    /// Leave the file out of the batch, with a warning
+    Skip,
+}
+
+/// What to do when a file lacks a tag field used by the template
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum OnMissingTag {
+    /// Stop with an error (default)
+    #[default]
+    Error,
+
+    /// Render the field as empty text
+    Empty,
+}
+
+/// What to do with a file when the --exec command fails
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum OnExecFailure {
+    /// Stop with an error (default)
+    #[default]
+    Error,
+
+    /// Leave the file out of the batch, with a warning
+    Skip,
+}
+
+/// When to color operations in the output
+#[derive(Copy, Clone, Debug, Default, clap::ValueEnum)]
+pub enum ColorMode {
+    /// Color output written to a terminal (default)
+    #[default]
+    Auto,
+
+    /// Always color output
+    Always,
+
+    /// Never color output
+    Never,
 }
 
-#[derive(Copy, Clone, Debug)]
+/// How errors are reported
+#[derive(Copy, Clone, Debug, Default, clap::ValueEnum)]
+pub enum ErrorFormat {
+    /// Human-readable text (default)
+    #[default]
+    Text,
+
+    /// A single-line JSON object, with conflicts broken down into their parts
+    Json,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
 pub enum ExecutionMode {
     Copy,
     Move,
+    #[default]
     Preview,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Args {
     pub template: String,
+    pub template_file: Option<PathBuf>,
+    pub format: Option<String>,
     pub paths: Vec<String>,
     pub pattern: Option<Regex>,
+    pub pattern_ignore_case: bool,
+    pub on_empty: OnEmpty,
+    pub on_missing_tag: OnMissingTag,
     pub start: u32,
     pub execution: ExecutionMode,
     pub sort: SortMode,
+    pub skip_identical: bool,
+    pub update: bool,
+    pub width: usize,
+    pub table: bool,
+    pub color: ColorMode,
+    pub head_tail: Option<usize>,
+    pub min_width: usize,
+    pub explain: bool,
+    pub by_dir: bool,
+    pub show_unchanged: bool,
+    pub show_sort_key: bool,
+    pub time: bool,
+    pub temp_dir: Option<PathBuf>,
+    pub min_free_space: u64,
+    pub no_extension: bool,
+    pub quiet: u8,
+    pub match_path: bool,
+    pub match_index: usize,
+    pub fold_case: bool,
+    pub out_dir: Option<PathBuf>,
+    pub parents: bool,
+    pub mkdirs: bool,
+    pub only: Option<glob::Pattern>,
+    pub exclude_regex: Option<Regex>,
+    pub walk_order: WalkOrder,
+    pub strategy: StrategyMode,
+    pub interactive: bool,
+    pub quiet_conflicts: bool,
+    pub error_format: ErrorFormat,
+    pub continue_on_error: bool,
+    pub atomic: bool,
+    pub verify: bool,
+    pub check_permissions: bool,
+    pub yes: bool,
+    pub confirm_threshold: usize,
+    pub emit_script: Option<PathBuf>,
+    pub keep_original: bool,
+    pub backup_dir: Option<PathBuf>,
+    pub map: Option<PathBuf>,
+    pub from_file0: Option<PathBuf>,
+    pub strict: bool,
+    pub write_map: Option<PathBuf>,
+    pub log: Option<PathBuf>,
+    pub count_only: bool,
+    pub replacement: Option<String>,
+    pub strip_match: bool,
+    pub prefix: Option<String>,
+    pub suffix: Option<String>,
+    pub strip_prefix: Option<String>,
+    pub strip_suffix: Option<String>,
+    pub transform: Vec<Transform>,
+    pub ext_pattern: Option<Regex>,
+    pub ext_replace: Option<String>,
+    pub detect_extension: bool,
+    pub normalize: Option<char>,
+    pub collapse_spaces: bool,
+    pub sidecar: Option<String>,
+    pub exec: Option<String>,
+    pub on_exec_failure: OnExecFailure,
+    pub lossy_names: bool,
+    pub ascii: bool,
+    pub counter_per_ext: bool,
+    pub reset_on_change: Option<String>,
+    pub width_from_capture: bool,
+    pub zero_pad_original: bool,
+    pub continue_numbering: bool,
+    pub insert_at: Option<u32>,
+    pub range: Option<Range<usize>>,
+    pub order_by: Option<PathBuf>,
+    pub range_absolute: bool,
+    pub modulo: Option<u32>,
+    pub skip_numbers: Vec<Range<usize>>,
+    pub group_sep: Option<char>,
+    pub pad_char: Option<char>,
+    pub path_sep_replacement: Option<char>,
+    pub number_collisions: bool,
+}
+
+impl Args {
+    /// Whether output written to `stream` is colored, which with --color auto depends on the stream
+    pub fn color_for(&self, stream: &impl IsTerminal) -> bool {
+        match self.color {
+            ColorMode::Auto => stream.is_terminal(),
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        }
+    }
+
+    /// Per-operation output is shown unless at least one --quiet flag is given
+    pub fn show_operations(&self) -> bool {
+        self.quiet == 0
+    }
+
+    /// The summary is shown unless --quiet is given twice
+    pub fn show_summary(&self) -> bool {
+        self.quiet < 2
+    }
 }
 
 impl Args {
     pub fn parse() -> Self {
-        use clap::ArgGroup;
+        Self::parse_from(std::env::args_os())
+    }
 
+    /// Parse options from `args` rather than the command line, exiting on error
+    pub fn parse_from<T: Into<OsString> + Clone>(args: impl IntoIterator<Item = T>) -> Self {
         #[derive(Clone, Debug, Parser)]
         struct Template {
             /// Rename templates are used to replace the file stem using replacement tokens. Available replacement tokens include n and o to replace with the number and original filename.
             ///
             /// Use n:2 for [01, 02, ...] and n:3 for [001, 002, ...] etc. The same thing works with filenames: o:4 for "foobar" will cause "foob" to be included in the filename.
             ///
-            /// Enclose replacement tokens in {}, e.g. {n}. Tokens include [0, n] (numeric) and [f, o] (filename).
-            template: String,
+            /// Enclose replacement tokens in {}, e.g. {n}. Tokens include [0, n] (numeric), z (zero-based numeric), [f, o] (filename, or the part selected by --pattern) and [F, O] (the original file stem, ignoring --pattern).
+            #[structopt(required_unless_present_any = ["map", "count_only", "replacement", "strip_match", "prefix", "suffix", "strip_prefix", "strip_suffix", "transform", "organize_by_date", "exec", "template_file", "format"])]
+            template: Option<String>,
 
             /// Paths (glob patterns or specific files) to be moved
             paths: Vec<String>,
 
+            /// Read the template from a file instead of the command line.
+            ///
+            /// A trailing newline is ignored. When given, every positional argument is treated as a path.
+            #[structopt(long)]
+            template_file: Option<PathBuf>,
+
+            /// Assemble each name from the pattern's capture groups, e.g. --format '{3}-{2}-{1} {n}'
+            ///
+            /// Only {1}..{N} (capture groups) and {n} (the sequence number) are recognized; any other text is used as-is. When given, every positional argument is treated as a path.
+            #[structopt(long, requires = "pattern", conflicts_with = "template_file")]
+            format: Option<String>,
+
+            /// Rename files according to a CSV file of old,new rows instead of a template.
+            ///
+            /// Rows naming files which do not exist are skipped with a warning.
+            #[structopt(long, conflicts_with = "paths")]
+            map: Option<PathBuf>,
+
+            /// After copying or moving, write the old,new pair of each file actually copied or moved to this CSV file.
+            ///
+            /// The file can be passed back with --map, e.g. with its columns swapped to undo the batch.
+            #[structopt(long)]
+            write_map: Option<PathBuf>,
+
+            /// Append a record of every copy or move to this file, as one JSON object per line.
+            ///
+            /// Each record holds the time, mode, source, target and result; failures are recorded too.
+            #[structopt(long)]
+            log: Option<PathBuf>,
+
+            /// Read additional paths from a file of NUL-separated entries, e.g. from `find -print0`.
+            ///
+            /// Filenames containing newlines are handled. Entries are treated like paths given on the command line.
+            #[structopt(long, conflicts_with = "map")]
+            from_file0: Option<PathBuf>,
+
+            /// Fail if any path argument matches no files, rather than only warning about it.
+            #[structopt(long)]
+            strict: bool,
+
+            /// Print the number of files matched by the given paths and exit.
+            ///
+            /// No template is required in this mode.
+            #[structopt(long, conflicts_with = "map")]
+            count_only: bool,
+
+            /// When walking a directory, include only files matching this glob.
+            ///
+            /// The glob is matched against both the file name and the path relative to the directory.
+            #[structopt(long)]
+            only: Option<glob::Pattern>,
+
+            /// Skip files whose full path matches this regular expression, e.g. '\.tmp$|~$'
+            ///
+            /// This applies to every input, whether a file, directory or glob.
+            #[structopt(long)]
+            exclude_regex: Option<Regex>,
+
+            /// The order in which files are discovered while walking a directory.
+            ///
+            /// Sort modes (including the default, --path) reorder the result, so this only matters where sorting leaves order to the walk, e.g. for ties.
+            #[structopt(long, value_enum, default_value_t)]
+            walk_order: WalkOrder,
+
             /// Use a regular expression to select part of the original filename.
             ///
             /// If the provided regular expression includes a capture group, the content of the capture group will be used. Otherwise, replacement templates will make use of the whole match.
             #[structopt(long)]
             pattern: Option<Regex>,
 
+            /// Match the pattern without regard to case, as with a leading (?i).
+            #[structopt(long, requires = "pattern")]
+            pattern_ignore_case: bool,
+
+            /// What to do with a file when the pattern selects an empty name from it.
+            #[structopt(long, value_enum, default_value_t, requires = "pattern")]
+            on_empty: OnEmpty,
+
+            /// What to do when a file lacks an ID3 tag field the template uses, e.g. {artist}.
+            #[structopt(long, value_enum, default_value_t)]
+            on_missing_tag: OnMissingTag,
+
+            /// Replace every match of the pattern in the file stem, e.g. --pattern ' ' --replacement _
+            ///
+            /// Capture groups may be referenced as $1, $2, etc. When given, the template is {o} and every positional argument is treated as a path.
+            #[structopt(long, requires = "pattern", conflicts_with = "match_path")]
+            replacement: Option<String>,
+
+            /// Remove every match of the pattern from the file stem, then collapse leftover whitespace.
+            ///
+            /// When given, the template is {o} and every positional argument is treated as a path.
+            #[structopt(long, requires = "pattern", conflicts_with_all = ["replacement", "match_path"])]
+            strip_match: bool,
+
+            /// Add text before each file stem. When given, the template is {o} and every positional argument is treated as a path.
+            #[structopt(long)]
+            prefix: Option<String>,
+
+            /// Add text after each file stem (but before the extension). When given, the template is {o} and every positional argument is treated as a path.
+            #[structopt(long)]
+            suffix: Option<String>,
+
+            /// Remove this text from the start of each file stem, where present, e.g. --strip-prefix IMG_
+            ///
+            /// Applied before --prefix and --suffix. When given, the template is {o} and every positional argument is treated as a path.
+            #[structopt(long)]
+            strip_prefix: Option<String>,
+
+            /// Remove this text from the end of each file stem, where present, e.g. --strip-suffix _final
+            ///
+            /// Applied before --prefix and --suffix. When given, the template is {o} and every positional argument is treated as a path.
+            #[structopt(long)]
+            strip_suffix: Option<String>,
+
+            /// Change the original name, e.g. --transform strip:IMG_ --transform lower; may be repeated, and applies in order.
+            ///
+            /// Transforms: lower, upper, trim, strip:TEXT (from either end), prefix:TEXT, suffix:TEXT, replace:FROM=TO. When given, the template is {o} and every positional argument is treated as a path.
+            #[structopt(long, value_parser = parse_transform)]
+            transform: Vec<Transform>,
+
+            /// A regular expression applied to each original extension (without the dot), e.g. '(?i)^jpe?g$'
+            #[structopt(long, requires = "ext_replace", conflicts_with = "no_extension")]
+            ext_pattern: Option<Regex>,
+
+            /// Replace every match of --ext-pattern in the extension, e.g. --ext-replace jpg
+            ///
+            /// Capture groups may be referenced as $1, $2, etc.
+            #[structopt(long, requires = "ext_pattern")]
+            ext_replace: Option<String>,
+
+            /// Set each file's extension from its content (e.g. .png for a PNG image), where it can be identified.
+            ///
+            /// Files of unknown type keep their original extension.
+            #[structopt(long, conflicts_with = "no_extension")]
+            detect_extension: bool,
+
+            /// Make each new filename web-safe: lowercase, with words joined by a separator, e.g. --normalize=_ (Default: -)
+            ///
+            /// Runs of whitespace and separators collapse to one separator. This applies to the whole filename, extension included.
+            #[structopt(long, num_args = 0..=1, require_equals = true, default_missing_value = "-")]
+            normalize: Option<char>,
+
+            /// Replace each run of whitespace in the new file stem with a single space.
+            ///
+            /// The extension is left alone.
+            #[structopt(long)]
+            collapse_spaces: bool,
+
+            /// Name files by their sidecars, e.g. photo.jpg.name for photo.jpg with --sidecar name.
+            ///
+            /// The contents of a file's sidecar replace its new stem; files without one are named by the template, and are numbered as if the others were not there. Sidecar files are never renamed themselves.
+            #[structopt(long, value_name = "EXT")]
+            sidecar: Option<String>,
+
+            /// Name each file with a shell command, e.g. --exec 'my-namer {}', whose output becomes the new stem.
+            ///
+            /// {} stands for the path of the file, quoted for the shell. When given, every positional argument is treated as a path.
+            #[structopt(long, value_name = "COMMAND")]
+            exec: Option<String>,
+
+            /// What to do with a file when the --exec command fails or prints nothing.
+            #[structopt(long, value_enum, default_value_t, requires = "exec")]
+            on_exec_failure: OnExecFailure,
+
+            /// Match the pattern against the full path rather than just the file stem.
+            ///
+            /// Path separators are platform-specific; use [/\\] to match either. Files whose path does not match fall back to their original stem.
+            #[structopt(long, requires = "pattern")]
+            match_path: bool,
+
+            /// Use this match of the pattern when it matches more than once, counting from 0. (Default: 0)
+            ///
+            /// Naming a match which does not exist is an error.
+            #[structopt(long, requires = "pattern", default_value_t)]
+            match_index: usize,
+
+            /// Transliterate the original filename to ASCII, e.g. "café" becomes "cafe".
+            ///
+            /// Accents are removed by Unicode decomposition; other scripts are approximated.
+            #[structopt(long)]
+            ascii: bool,
+
+            /// Allow filenames which are not valid UTF-8, replacing invalid bytes with U+FFFD.
+            ///
+            /// By default, such files are refused rather than silently mangled.
+            #[structopt(long)]
+            lossy_names: bool,
+
+            /// Treat names differing only in case as the same file when checking for conflicts.
+            ///
+            /// Always on for macOS and Windows, whose filesystems are usually case-insensitive.
+            #[structopt(long)]
+            fold_case: bool,
+
+            /// Place every renamed file in this directory instead of alongside its source.
+            #[structopt(long)]
+            out_dir: Option<PathBuf>,
+
+            /// Under --out-dir, recreate each file's directory relative to the input it was found in.
+            ///
+            /// For a directory argument, that is the directory itself; for a glob, the part before the first wildcard.
+            #[structopt(long, requires = "out_dir")]
+            parents: bool,
+
+            /// Create any directories a target needs, e.g. the year folders of a template like '{g:1}/{o}'.
+            ///
+            /// Without it, a target in a directory which does not exist is an error.
+            #[structopt(long)]
+            mkdirs: bool,
+
+            /// Move each file into a folder named for its modified date, e.g. --organize-by-date %Y-%m, keeping its name.
+            ///
+            /// Shorthand for the template '{m:FORMAT}/{o}' with --mkdirs; every positional argument is treated as a path.
+            #[structopt(long, value_name = "FORMAT", value_parser = parse_date_folder, conflicts_with_all = ["template_file", "format", "prefix", "suffix"])]
+            organize_by_date: Option<String>,
+
+            /// Do not re-append the original extension; the template controls the whole filename.
+            #[structopt(long)]
+            no_extension: bool,
+
+            /// Number each extension as its own sequence, e.g. img-1.jpg, img-1.png, img-2.jpg, ...
+            ///
+            /// Within each extension, files are numbered in sort order.
+            #[structopt(long)]
+            counter_per_ext: bool,
+
+            /// Restart numbering whenever this capture group of the pattern (a name or number) changes from one file to the next.
+            ///
+            /// For example, --pattern '^(?P<album>.+) - ' --reset-on-change album numbers each album's tracks from the start value.
+            #[structopt(long, requires = "pattern", conflicts_with_all = ["counter_per_ext", "insert_at"])]
+            reset_on_change: Option<String>,
+
+            /// Give files whose new names collide a numeric suffix, e.g. "photo (1).jpg", "photo (2).jpg".
+            ///
+            /// Files whose new names are unique are left alone.
+            #[structopt(long)]
+            number_collisions: bool,
+
+            /// The separator inserted between groups of digits by {n:group}. (Default: ,)
+            #[structopt(long)]
+            group_sep: Option<char>,
+
+            /// Pad numbers to the width of the largest number captured by the pattern, rather than to the number of files.
+            ///
+            /// This keeps new numbers aligned with numbers already in the filenames.
+            #[structopt(long, requires = "pattern")]
+            width_from_capture: bool,
+
+            /// Pad numbers captured by the pattern with zeros to the width of the widest one in the batch.
+            ///
+            /// With --pattern 'a(\d+)', the template a{o} turns a1, a22 and a333 into a001, a022 and a333.
+            #[structopt(long, requires = "pattern")]
+            zero_pad_original: bool,
+
+            /// Start numbering after the highest number already used by files in the destination.
+            ///
+            /// Existing files are matched against the template; files being renamed are not counted.
+            #[structopt(long)]
+            continue_numbering: bool,
+
+            /// Insert new files into an existing numbered sequence at this number, shifting later files up.
+            ///
+            /// Files already named by the template keep their order; any other files are numbered from here.
+            #[structopt(long, conflicts_with_all = ["continue_numbering", "counter_per_ext"])]
+            insert_at: Option<u32>,
+
+            /// Rename only this slice of the sorted files, counting from zero, e.g. --range 10..20 or --range 10..
+            ///
+            /// Inclusive ranges such as 10..=19 are also accepted. A range extending past the last file is clamped, with a warning.
+            #[structopt(long, value_parser = parse_range, conflicts_with = "map")]
+            range: Option<Range<usize>>,
+
+            /// Number files in the order their namesakes (files with the same stem) have in this directory.
+            ///
+            /// The directory is sorted like the input. Files without a namesake come last, in their usual order.
+            #[structopt(long, conflicts_with = "map")]
+            order_by: Option<PathBuf>,
+
+            /// Number files in a --range by their position among all the files, rather than from the start value.
+            #[structopt(long, requires = "range")]
+            range_absolute: bool,
+
+            /// Cycle numbers through this many values, e.g. 0, 1, 2, 3, 0, 1, ... with --start 0 --modulo 4.
+            ///
+            /// Numbers are padded to the width of the largest value in the cycle.
+            #[structopt(long, value_parser = clap::value_parser!(u32).range(1..))]
+            modulo: Option<u32>,
+
+            /// Never assign these numbers, e.g. --skip-numbers 13,40..=49; later files take the next free number instead.
+            ///
+            /// Numbers and ranges are separated by commas. Numbers are padded to the width of the last number used.
+            #[structopt(long, value_parser = parse_skip, value_delimiter = ',', conflicts_with = "modulo")]
+            skip_numbers: Vec<Range<usize>>,
+
+            /// Pad every number to at least this many digits, however few files there are.
+            #[structopt(long, default_value_t)]
+            min_width: usize,
+
+            /// The character used to pad numbers to their width, e.g. ' ' to right-align them. (Default: 0)
+            #[structopt(long)]
+            pad_char: Option<char>,
+
+            /// The character which replaces path separators in {pp}, the file's directory relative to its input. (Default: _)
+            #[structopt(long)]
+            path_sep_replacement: Option<char>,
+
             /// Start numbering at something other than 1.
             #[structopt(short, long)]
             start: Option<u32>,
 
+            /// Wrap operations longer than this many columns onto two lines (0 = never wrap).
+            ///
+            /// Defaults to the width of the terminal, or 80 when not writing to a terminal.
+            #[structopt(long)]
+            width: Option<usize>,
+
+            /// Preview operations as an aligned table, with sources right-aligned against their targets.
+            ///
+            /// Replaces wrapping at --width.
+            #[structopt(long)]
+            table: bool,
+
+            /// Color operations in the output: sources in red, targets in green.
+            ///
+            /// With auto, operations are colored only when writing to a terminal.
+            #[structopt(long, value_enum, default_value_t)]
+            color: ColorMode,
+
+            /// Preview only the first and last N operations, eliding those between them.
+            ///
+            /// The summary still counts every operation.
+            #[structopt(long, value_name = "N")]
+            head_tail: Option<usize>,
+
+            /// The order in which to apply operations.
+            ///
+            /// Forcing an order skips checking the other, which saves time for very large batches; conflicts are still reported.
+            #[structopt(long, value_enum, default_value_t)]
+            strategy: StrategyMode,
+
+            /// When no order avoids a conflict, offer to skip each conflicting operation and plan the rest.
+            ///
+            /// Prompts are written to stderr and answered on stdin.
+            #[structopt(long)]
+            interactive: bool,
+
+            /// When previewing, list conflicting operations as skipped instead of failing, and preview the rest.
+            ///
+            /// Copying and moving still refuse to run while conflicts remain.
+            #[structopt(long, conflicts_with = "interactive")]
+            quiet_conflicts: bool,
+
+            /// How to report errors on stderr; json is meant for scripts.
+            #[structopt(long, value_enum, default_value_t)]
+            error_format: ErrorFormat,
+
+            /// When previewing, explain why the selected iteration order was chosen.
+            #[structopt(long)]
+            explain: bool,
+
+            /// When previewing, count how many files land in each destination directory.
+            #[structopt(long)]
+            by_dir: bool,
+
+            /// When previewing, list files whose names would not change, marked with "=".
+            ///
+            /// Such files are counted but not listed by default.
+            #[structopt(long)]
+            show_unchanged: bool,
+
+            /// When previewing a date sort, print the date each file was sorted by before it.
+            #[structopt(long)]
+            show_sort_key: bool,
+
+            /// After copying or moving, print the elapsed time and the number of files handled per second.
+            #[structopt(long)]
+            time: bool,
+
+            /// Suppress per-operation output; pass twice to suppress the summary as well.
+            #[structopt(short, long, action = clap::ArgAction::Count)]
+            quiet: u8,
+
             #[command(flatten)]
             execution_opts: ExecutionOptions,
 
@@ -69,9 +656,77 @@ impl Args {
             #[structopt(long)]
             copy: bool,
 
-            /// Rename files
+            /// Rename files, after showing the plan and asking for confirmation
             #[structopt(short, long)]
             force: bool,
+
+            /// With --force, rename without asking for confirmation.
+            #[structopt(short, long, requires = "force")]
+            yes: bool,
+
+            /// When asking for confirmation, warn prominently if more than this many files will be moved.
+            #[structopt(long, default_value_t = 100)]
+            confirm_threshold: usize,
+
+            /// Keep going when an operation fails, then report every failure at the end.
+            #[structopt(long)]
+            continue_on_error: bool,
+
+            /// Apply all operations or none: if one fails, undo those already completed.
+            ///
+            /// With --copy, a copy which would overwrite an existing file is refused up front, since it could not be undone.
+            #[structopt(long, conflicts_with = "continue_on_error")]
+            atomic: bool,
+
+            /// After copying or moving, confirm that every target exists and every moved source is gone.
+            #[structopt(long)]
+            verify: bool,
+
+            /// When previewing or copying, check that every source can be read and every target directory written to.
+            ///
+            /// Unless copying, the directory of every source must be writable too. Problems are reported, and the run fails, before anything is copied or moved.
+            #[structopt(long, conflicts_with_all = ["force", "emit_script"])]
+            check_permissions: bool,
+
+            /// Instead of renaming, write the plan as a shell script of mv (or, with --copy, cp) commands, e.g. --emit-script=rename.sh (Default: stdout)
+            ///
+            /// Paths are single-quoted, and the commands run in an order which avoids conflicts.
+            #[structopt(
+                long,
+                num_args = 0..=1,
+                require_equals = true,
+                default_missing_value = "-",
+                conflicts_with = "force"
+            )]
+            emit_script: Option<PathBuf>,
+
+            /// When moving, first copy each source to <name>.orig so that the original survives.
+            #[structopt(long, requires = "force")]
+            keep_original: bool,
+
+            /// With --keep-original, put the copies in this directory under their original names instead.
+            #[structopt(long, requires = "keep_original")]
+            backup_dir: Option<PathBuf>,
+
+            /// When copying, skip destinations which already hold identical content.
+            #[structopt(long)]
+            skip_identical: bool,
+
+            /// When copying, skip destinations which are as new as or newer than their source.
+            #[structopt(long, requires = "copy")]
+            update: bool,
+
+            /// Directory used to stage files which must be copied between devices.
+            ///
+            /// Defaults to the destination's directory so that the final move stays on-device.
+            #[structopt(long)]
+            temp_dir: Option<PathBuf>,
+
+            /// When copying, leave at least this many bytes free on each destination filesystem. (Default: 0)
+            ///
+            /// Copies which would not fit are refused before anything is written.
+            #[structopt(long, default_value_t)]
+            min_free_space: u64,
         }
 
         impl ExecutionOptions {
@@ -86,50 +741,414 @@ impl Args {
             }
         }
 
-        #[derive(Clone, Debug, Parser)]
-        #[command(group = ArgGroup::new("sort"))]
-        struct SortOptions {
-            /// Sort files by created date when renaming.
-            #[structopt(short, long, group = "sort")]
-            created: bool,
-
-            /// Sort files by modified date when renaming.
-            #[structopt(short, long, group = "sort")]
-            modified: bool,
-
-            /// Sort files by path when renaming. (Default)
-            #[structopt(short, long, group = "sort")]
-            path: bool,
-        }
-
-        impl SortOptions {
-            fn into_enum(self) -> SortMode {
-                if self.created {
-                    SortMode::Created
-                } else if self.modified {
-                    SortMode::Modified
-                } else {
-                    SortMode::Path
-                }
-            }
-        }
-
         let Template {
             template,
             paths,
+            template_file,
+            format,
+            map,
+            from_file0,
+            strict,
+            write_map,
+            log,
+            count_only,
+            only,
+            exclude_regex,
+            walk_order,
             pattern,
+            pattern_ignore_case,
+            on_empty,
+            on_missing_tag,
+            replacement,
+            strip_match,
+            prefix,
+            suffix,
+            strip_prefix,
+            strip_suffix,
+            transform,
+            ext_pattern,
+            ext_replace,
+            detect_extension,
+            normalize,
+            collapse_spaces,
+            sidecar,
+            exec,
+            on_exec_failure,
+            match_path,
+            match_index,
+            ascii,
+            lossy_names,
+            fold_case,
+            out_dir,
+            parents,
+            mkdirs,
+            organize_by_date,
+            no_extension,
+            counter_per_ext,
+            reset_on_change,
+            width_from_capture,
+            zero_pad_original,
+            continue_numbering,
+            insert_at,
+            range,
+            order_by,
+            range_absolute,
+            modulo,
+            skip_numbers,
+            group_sep,
+            pad_char,
+            path_sep_replacement,
+            min_width,
+            number_collisions,
             start,
+            width,
+            table,
+            color,
+            head_tail,
+            strategy,
+            interactive,
+            quiet_conflicts,
+            error_format,
+            explain,
+            by_dir,
+            show_unchanged,
+            show_sort_key,
+            time,
+            quiet,
             execution_opts,
             sort_opts,
-        } = Parser::parse();
+        } = Parser::parse_from(args);
+
+        // Options which stand in for a template make every positional argument a path
+        let implied_by = [
+            (count_only, "--count-only"),
+            (template_file.is_some(), "--template-file"),
+            (format.is_some(), "--format"),
+            (replacement.is_some(), "--replacement"),
+            (strip_match, "--strip-match"),
+            (prefix.is_some(), "--prefix"),
+            (suffix.is_some(), "--suffix"),
+            (strip_prefix.is_some(), "--strip-prefix"),
+            (strip_suffix.is_some(), "--strip-suffix"),
+            (!transform.is_empty(), "--transform"),
+            (organize_by_date.is_some(), "--organize-by-date"),
+            (exec.is_some(), "--exec"),
+        ]
+        .into_iter()
+        .find_map(|(given, flag)| given.then_some(flag));
+        let (template, paths) = match implied_by {
+            Some(flag) => {
+                let implied = match &organize_by_date {
+                    Some(format) => format!("{{m:{}}}/{{o}}", format),
+                    None => String::from("{o}"),
+                };
+                let paths = implicit_paths(template, paths, flag).unwrap_or_else(|e| {
+                    clap::Error::raw(clap::error::ErrorKind::ArgumentConflict, e + "\n").exit()
+                });
+                (implied, paths)
+            }
+            None => (template.unwrap_or_default(), paths),
+        };
 
         Args {
             template,
+            template_file,
+            format,
             paths,
+            map,
+            from_file0,
+            strict,
+            write_map,
+            log,
+            count_only,
+            only,
+            exclude_regex,
+            walk_order,
             pattern,
+            pattern_ignore_case,
+            on_empty,
+            on_missing_tag,
+            replacement,
+            strip_match,
+            prefix,
+            suffix,
+            strip_prefix,
+            strip_suffix,
+            transform,
+            ext_pattern,
+            ext_replace,
+            detect_extension,
+            normalize,
+            collapse_spaces,
+            sidecar,
+            exec,
+            on_exec_failure,
+            match_path,
+            match_index,
+            ascii,
+            lossy_names,
+            fold_case: fold_case || cfg!(any(target_os = "macos", target_os = "windows")),
+            out_dir,
+            parents,
+            mkdirs: mkdirs || organize_by_date.is_some(),
+            no_extension,
+            counter_per_ext,
+            reset_on_change,
+            width_from_capture,
+            zero_pad_original,
+            continue_numbering,
+            insert_at,
+            range,
+            order_by,
+            range_absolute,
+            modulo,
+            skip_numbers,
+            group_sep,
+            pad_char,
+            path_sep_replacement,
+            min_width,
+            number_collisions,
             start: start.unwrap_or(1),
+            width: width.unwrap_or_else(terminal_width),
+            table,
+            color,
+            head_tail,
+            strategy,
+            interactive,
+            quiet_conflicts,
+            error_format,
+            explain,
+            by_dir,
+            show_unchanged,
+            show_sort_key,
+            time,
+            quiet,
+            skip_identical: execution_opts.skip_identical,
+            update: execution_opts.update,
+            continue_on_error: execution_opts.continue_on_error,
+            atomic: execution_opts.atomic,
+            verify: execution_opts.verify,
+            check_permissions: execution_opts.check_permissions,
+            yes: execution_opts.yes,
+            confirm_threshold: execution_opts.confirm_threshold,
+            emit_script: execution_opts.emit_script.clone(),
+            keep_original: execution_opts.keep_original,
+            backup_dir: execution_opts.backup_dir.clone(),
+            temp_dir: execution_opts.temp_dir.clone(),
+            min_free_space: execution_opts.min_free_space,
             execution: execution_opts.into_enum(),
-            sort: sort_opts.into_enum(),
+            sort: sort_opts.into_enum().unwrap_or_else(|e| {
+                clap::Error::raw(clap::error::ErrorKind::ArgumentConflict, e + "\n").exit()
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(group = ArgGroup::new("sort"))]
+struct SortOptions {
+    /// Sort files by created date when renaming.
+    #[structopt(short, long, group = "sort")]
+    created: bool,
+
+    /// Sort files by modified date when renaming.
+    #[structopt(short, long, group = "sort")]
+    modified: bool,
+
+    /// Sort files by path when renaming. (Default)
+    #[structopt(short, long, group = "sort")]
+    path: bool,
+
+    /// Keep files in the order given on the command line instead of sorting them.
+    ///
+    /// Files found in a directory follow the order of the walk. Files given more than once are renamed once.
+    #[structopt(long, group = "sort")]
+    as_given: bool,
+
+    /// Sort files by their Finder tags when renaming. (macOS only)
+    ///
+    /// Files are ordered by tag name; untagged files come last, in path order.
+    #[structopt(long, group = "sort")]
+    tag: bool,
+}
+
+impl SortOptions {
+    /// The group should make these flags exclusive; if more than one is set anyway, say so
+    /// rather than quietly picking one.
+    fn into_enum(self) -> Result<SortMode, String> {
+        let flags = [
+            (self.created, "--created", SortMode::Created),
+            (self.modified, "--modified", SortMode::Modified),
+            (self.path, "--path", SortMode::Path),
+            (self.as_given, "--as-given", SortMode::AsGiven),
+            (self.tag, "--tag", SortMode::Tag),
+        ];
+        let mut set = flags.iter().filter(|(set, ..)| *set);
+
+        #[cfg(not(target_os = "macos"))]
+        if self.tag {
+            return Err("--tag is only supported on macOS".into());
         }
+
+        match (set.next(), set.next()) {
+            (None, _) => Ok(SortMode::Path),
+            (Some(&(_, _, mode)), None) => Ok(mode),
+            (Some((_, first, _)), Some((_, second, _))) => Err(format!(
+                "only one sort order may be given: {} conflicts with {}",
+                first, second
+            )),
+        }
+    }
+}
+
+/// Parse an entry of --skip-numbers: a single number, or a range as for --range which must end
+fn parse_skip(text: &str) -> Result<Range<usize>, String> {
+    if !text.contains("..") {
+        let number: usize = text
+            .parse()
+            .map_err(|e| format!("invalid number '{}': {}", text, e))?;
+        return Ok(number..number + 1);
+    }
+
+    let range = parse_range(text)?;
+    if range.end == usize::MAX {
+        return Err(format!("ranges of skipped numbers must end: {}", text));
+    }
+    Ok(range)
+}
+
+/// The paths given alongside an option which stands in for the template, `flag`
+///
+/// The first positional argument is a path too, but one which looks like a template is refused
+/// rather than taken for a glob.
+fn implicit_paths(
+    first: Option<String>,
+    paths: Vec<String>,
+    flag: &str,
+) -> Result<Vec<String>, String> {
+    if let Some(first) = first.as_deref().filter(|&first| looks_like_template(first)) {
+        return Err(format!(
+            "{} takes the place of a template, so '{}' cannot be one; give only paths",
+            flag, first
+        ));
+    }
+    Ok(first.into_iter().chain(paths).collect())
+}
+
+/// True if `arg` names no file and contains a replacement token
+fn looks_like_template(arg: &str) -> bool {
+    if Path::new(arg).exists() {
+        return false;
+    }
+    match TemplateParser::new().parse(arg) {
+        Ok(template) => template
+            .segments()
+            .any(|segment| !matches!(segment, Segment::Literal(_))),
+        Err(_) => true,
+    }
+}
+
+/// Parse the --organize-by-date format, which becomes part of a template and so may hold no braces
+fn parse_date_folder(format: &str) -> Result<String, String> {
+    if format.is_empty() {
+        return Err(String::from("the date format may not be empty"));
+    }
+    if format.contains(['{', '}']) {
+        return Err(format!(
+            "the date format may not contain braces: {}",
+            format
+        ));
+    }
+    Ok(format.into())
+}
+
+/// Parse a Rust-style range: `a..b`, `a..=b`, `a..` or `..b`
+fn parse_range(text: &str) -> Result<Range<usize>, String> {
+    let (start, end) = text
+        .split_once("..")
+        .ok_or_else(|| format!("expected a range such as 10..20: {}", text))?;
+    let parse = |bound: &str| {
+        bound
+            .parse::<usize>()
+            .map_err(|e| format!("invalid range bound '{}': {}", bound, e))
+    };
+
+    let start = if start.is_empty() { 0 } else { parse(start)? };
+    let end = match end.strip_prefix('=') {
+        Some(end) => parse(end)?.saturating_add(1),
+        None if end.is_empty() => usize::MAX,
+        None => parse(end)?,
+    };
+
+    if start > end {
+        return Err(format!("range starts after it ends: {}", text));
+    }
+    Ok(start..end)
+}
+
+fn terminal_width() -> usize {
+    const DEFAULT_WIDTH: usize = 80;
+
+    terminal_size::terminal_size()
+        .map(|(width, _)| width.0 as usize)
+        .unwrap_or(DEFAULT_WIDTH)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::{SortMode, SortOptions};
+
+    #[test]
+    fn implied_template_refuses_a_given_one() {
+        let paths = vec![String::from("b.jpg")];
+        assert_eq!(
+            vec!["*.jpg", "b.jpg"],
+            super::implicit_paths(Some("*.jpg".into()), paths.clone(), "--prefix").unwrap()
+        );
+        assert_eq!(
+            "--prefix takes the place of a template, so '{n}' cannot be one; give only paths",
+            super::implicit_paths(Some("{n}".into()), paths.clone(), "--prefix").unwrap_err()
+        );
+
+        // A token which fails to parse still marks a template
+        assert!(
+            super::implicit_paths(Some("{now:%Q}".into()), paths.clone(), "--strip-match").is_err()
+        );
+        assert!(
+            super::implicit_paths(Some("{o}_x".into()), paths.clone(), "--replacement").is_err()
+        );
+        assert!(super::implicit_paths(Some("{O:4}".into()), paths, "--transform").is_err());
+    }
+
+    #[test]
+    fn date_folders_hold_no_braces() {
+        assert_eq!("%Y/%m", super::parse_date_folder("%Y/%m").unwrap());
+        assert!(super::parse_date_folder("%Y}-{n").is_err());
+        assert!(super::parse_date_folder("").is_err());
+    }
+
+    #[test]
+    fn sort_flags_conflict() {
+        assert!(SortOptions::try_parse_from(["mmv", "--created", "--modified"]).is_err());
+        assert!(matches!(
+            SortOptions::try_parse_from(["mmv", "--modified"])
+                .unwrap()
+                .into_enum(),
+            Ok(SortMode::Modified)
+        ));
+
+        let options = SortOptions {
+            created: true,
+            modified: false,
+            path: true,
+            as_given: false,
+            tag: false,
+        };
+        assert_eq!(
+            "only one sort order may be given: --created conflicts with --path",
+            options.into_enum().unwrap_err()
+        );
     }
 }
@@ -0,0 +1,79 @@
+use std::{io, path::Path};
+
+use crate::template::TagField;
+
+/// The fields of an audio file's ID3 tag which templates can use
+#[derive(Debug, Default)]
+pub struct AudioTags {
+    artist: Option<String>,
+    album: Option<String>,
+    title: Option<String>,
+    track: Option<u32>,
+}
+
+impl AudioTags {
+    /// Read the ID3 tag of a file; files without one have no fields
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let tag = match id3::Tag::read_from_path(path) {
+            Ok(tag) => tag,
+            Err(id3::Error {
+                kind: id3::ErrorKind::NoTag,
+                ..
+            }) => return Ok(Self::default()),
+            Err(id3::Error {
+                kind: id3::ErrorKind::Io(e),
+                ..
+            }) => return Err(e),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        };
+
+        use id3::TagLike;
+        Ok(Self {
+            artist: tag.artist().map(String::from),
+            album: tag.album().map(String::from),
+            title: tag.title().map(String::from),
+            track: tag.track(),
+        })
+    }
+
+    pub fn get(&self, field: TagField) -> Option<String> {
+        match field {
+            TagField::Artist => self.artist.clone(),
+            TagField::Album => self.album.clone(),
+            TagField::Title => self.title.clone(),
+            TagField::Track => self.track.map(|track| track.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use id3::TagLike;
+
+    use crate::template::TagField;
+
+    use super::AudioTags;
+
+    #[test]
+    fn reads_id3_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("song.mp3");
+        std::fs::write(&path, b"").unwrap();
+        assert!(AudioTags::read(&path)
+            .unwrap()
+            .get(TagField::Artist)
+            .is_none());
+
+        let mut tag = id3::Tag::new();
+        tag.set_artist("Low");
+        tag.set_title("Words");
+        tag.set_track(7);
+        tag.write_to_path(&path, id3::Version::Id3v24).unwrap();
+
+        let tags = AudioTags::read(&path).unwrap();
+        assert_eq!(Some("Low".into()), tags.get(TagField::Artist));
+        assert_eq!(Some("Words".into()), tags.get(TagField::Title));
+        assert_eq!(Some("7".into()), tags.get(TagField::Track));
+        assert_eq!(None, tags.get(TagField::Album));
+    }
+}
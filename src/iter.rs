@@ -1,23 +1,39 @@
 use std::{
+    borrow::Cow,
     collections::HashMap,
     error, fmt,
     path::{Path, PathBuf},
 };
 
 pub struct DataTracker<'a> {
-    paths: HashMap<&'a Path, bool>,
+    paths: HashMap<Cow<'a, Path>, bool>,
+
+    /// Targets claimed so far, with the step and source which claimed them
+    targets: HashMap<Cow<'a, Path>, (usize, &'a Path)>,
+
+    /// Compare paths without regard to case, as on case-insensitive filesystems
+    fold_case: bool,
 }
 
 impl<'a> DataTracker<'a> {
-    pub fn new(paths: &'a [impl AsRef<Path>]) -> Self {
+    /// Create a tracker which, if `fold_case` is set, treats paths differing only in case as equal
+    pub fn new(paths: &'a [impl AsRef<Path>], fold_case: bool) -> Self {
+        let paths = paths
+            .iter()
+            .map(|path| (key(path.as_ref(), fold_case), true))
+            .collect();
+
         Self {
-            paths: paths.iter().map(|path| (path.as_ref(), true)).collect(),
+            paths,
+            targets: HashMap::new(),
+            fold_case,
         }
     }
 
     /// Reset file states to conflict
     pub fn reset(&mut self) {
         self.paths.iter_mut().for_each(|kv| *kv.1 = true);
+        self.targets.clear();
     }
 
     pub fn check_iteration(
@@ -25,49 +41,137 @@ impl<'a> DataTracker<'a> {
         iteration: impl Iterator<Item = Operation<'a>>,
     ) -> Result<(), Conflict> {
         self.reset(); // Just in case
+        self.check(iteration)
+    }
+
+    /// Check only that no two operations claim the same target
+    ///
+    /// This is all that matters when every file is first moved out of the way to a staging name.
+    pub fn check_claims(
+        &mut self,
+        iteration: impl Iterator<Item = Operation<'a>>,
+    ) -> Result<(), Conflict> {
+        self.reset();
+        self.paths.iter_mut().for_each(|kv| *kv.1 = false);
+        self.check(iteration)
+    }
+
+    fn check(&mut self, iteration: impl Iterator<Item = Operation<'a>>) -> Result<(), Conflict> {
+        for (step, operation) in iteration.enumerate() {
+            let step = step + 1;
+            let from = key(operation.from, self.fold_case);
+            let to = key(operation.to, self.fold_case);
 
-        for operation in iteration {
-            if let Some(from) = self.paths.get_mut(operation.from) {
+            if let Some(from) = self.paths.get_mut(&from) {
                 *from = false;
             }
 
-            let is_conflict = self.paths.get(operation.to).copied().unwrap_or_default();
+            let is_conflict = self.paths.get(&to).copied().unwrap_or_default();
             if is_conflict {
-                return Err(operation.into_conflict());
+                return Err(operation.into_conflict(step, Cause::Unmoved));
+            }
+
+            if let Some(&(claimed_at, claimed_by)) = self.targets.get(&to) {
+                let cause = Cause::Claimed {
+                    step: claimed_at,
+                    from: claimed_by.into(),
+                };
+                return Err(operation.into_conflict(step, cause));
             }
+
+            self.targets.insert(to, (step, operation.from));
         }
         Ok(())
     }
 }
 
+/// The key under which a path is tracked, lowercased when folding case
+fn key(path: &Path, fold_case: bool) -> Cow<'_, Path> {
+    if fold_case {
+        Cow::Owned(path.to_string_lossy().to_lowercase().into())
+    } else {
+        Cow::Borrowed(path)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
 pub struct Operation<'a> {
     pub from: &'a Path,
     pub to: &'a Path,
 }
 
 impl Operation<'_> {
-    pub fn into_conflict(self) -> Conflict {
+    pub fn into_conflict(self, step: usize, cause: Cause) -> Conflict {
         Conflict {
             from: self.from.into(),
             to: self.to.into(),
+            step,
+            cause,
         }
     }
 }
 
+/// The reason an operation's target is unavailable
+#[derive(Clone, Debug)]
+pub enum Cause {
+    /// The target is a source which has not yet been moved out of the way
+    Unmoved,
+
+    /// The target was already claimed by an earlier operation
+    Claimed { step: usize, from: PathBuf },
+}
+
 #[derive(Clone, Debug)]
 pub struct Conflict {
     pub from: PathBuf,
     pub to: PathBuf,
+
+    /// The (one-based) position of the conflicting operation in its iteration
+    pub step: usize,
+    pub cause: Cause,
+}
+
+impl Conflict {
+    /// Describe this conflict as a JSON object, for scripts
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut value = serde_json::json!({
+            "error": "conflict",
+            "from": self.from.to_string_lossy(),
+            "to": self.to.to_string_lossy(),
+            "step": self.step,
+        });
+
+        match &self.cause {
+            Cause::Unmoved => value["cause"] = "unmoved".into(),
+            Cause::Claimed { step, from } => {
+                value["cause"] = "claimed".into();
+                value["claimed_at"] = (*step).into();
+                value["claimed_by"] = from.to_string_lossy().into();
+            }
+        }
+        value
+    }
 }
 
 impl fmt::Display for Conflict {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(
             f,
-            "move conflict\n  {}\n  {}",
+            "move conflict at step {}\n  {}\n  {}",
+            self.step,
             self.from.display(),
             self.to.display()
-        )
+        )?;
+
+        match &self.cause {
+            Cause::Unmoved => writeln!(f, "target has not been moved out of the way yet"),
+            Cause::Claimed { step, from } => writeln!(
+                f,
+                "target was already claimed at step {} by\n  {}",
+                step,
+                from.display()
+            ),
+        }
     }
 }
 
@@ -85,6 +189,17 @@ impl MultimodeConflict {
     }
 }
 
+impl MultimodeConflict {
+    /// Describe both conflicts as a JSON object, for scripts
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "error": "conflict",
+            "forward": self.forward.to_json(),
+            "reverse": self.reverse.to_json(),
+        })
+    }
+}
+
 impl fmt::Display for MultimodeConflict {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "{}\n{}\ntoo many conflicts", self.forward, self.reverse)
@@ -93,6 +208,26 @@ impl fmt::Display for MultimodeConflict {
 
 impl error::Error for MultimodeConflict {}
 
+/// The order in which a batch of operations is applied
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Strategy {
+    Forward,
+    Reverse,
+
+    /// Every file is moved to a temporary name, then from there to its target
+    Stage,
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Strategy::Forward => f.write_str("forward"),
+            Strategy::Reverse => f.write_str("reverse"),
+            Strategy::Stage => f.write_str("staged"),
+        }
+    }
+}
+
 pub struct Forward<'a, T> {
     idx: usize,
     from: &'a [T],
@@ -167,7 +302,7 @@ impl<'a, T: AsRef<Path>> Iterator for Reverse<'a, T> {
 
 #[cfg(test)]
 mod tests {
-    use super::{DataTracker, Forward, Reverse};
+    use super::{DataTracker, Forward, MultimodeConflict, Reverse};
 
     #[test]
     fn forward() {
@@ -191,16 +326,88 @@ mod tests {
     fn must_reverse_rename() {
         let from = &["00", "01", "02"];
         let to = &["01", "02", "03"];
-        let mut tracker = DataTracker::new(from);
+        let mut tracker = DataTracker::new(from, false);
         assert!(tracker.check_iteration(Forward::new(from, to)).is_err());
         assert!(tracker.check_iteration(Reverse::new(from, to)).is_ok());
     }
 
+    #[test]
+    fn duplicate_targets_conflict() {
+        let from = &["a/00", "b/00"];
+        let to = &["out/00", "out/00"];
+        let mut tracker = DataTracker::new(from, false);
+        assert!(tracker.check_iteration(Forward::new(from, to)).is_err());
+        assert!(tracker.check_iteration(Reverse::new(from, to)).is_err());
+    }
+
+    #[test]
+    fn conflicts_describe_their_cause() {
+        let from = &["00", "01", "02"];
+        let to = &["01", "02", "03"];
+        let mut tracker = DataTracker::new(from, false);
+        let conflict = tracker.check_iteration(Forward::new(from, to)).unwrap_err();
+        assert_eq!(
+            "move conflict at step 1\n  00\n  01\ntarget has not been moved out of the way yet\n",
+            conflict.to_string()
+        );
+
+        let from = &["a/00", "a/01", "b/00"];
+        let to = &["out/00", "out/01", "out/00"];
+        let mut tracker = DataTracker::new(from, false);
+        let conflict = tracker.check_iteration(Forward::new(from, to)).unwrap_err();
+        assert_eq!(
+            "move conflict at step 3\n  b/00\n  out/00\ntarget was already claimed at step 1 by\n  a/00\n",
+            conflict.to_string()
+        );
+    }
+
+    #[test]
+    fn case_folded_targets_conflict() {
+        let from = &["a.jpg", "b.jpg"];
+        let to = &["Photo.jpg", "photo.jpg"];
+
+        let mut tracker = DataTracker::new(from, false);
+        assert!(tracker.check_iteration(Forward::new(from, to)).is_ok());
+
+        let mut tracker = DataTracker::new(from, true);
+        assert!(tracker.check_iteration(Forward::new(from, to)).is_err());
+        assert!(tracker.check_iteration(Reverse::new(from, to)).is_err());
+
+        // Changing only the case of a name is not a conflict with itself
+        let from = &["photo.jpg"];
+        let to = &["Photo.jpg"];
+        let mut tracker = DataTracker::new(from, true);
+        assert!(tracker.check_iteration(Forward::new(from, to)).is_ok());
+    }
+
+    #[test]
+    fn conflicts_as_json() {
+        let from = &["a/00", "a/01", "b/00"];
+        let to = &["out/00", "out/01", "out/00"];
+        let mut tracker = DataTracker::new(from, false);
+        let forward = tracker.check_iteration(Forward::new(from, to)).unwrap_err();
+        assert_eq!(
+            r#"{"cause":"claimed","claimed_at":1,"claimed_by":"a/00","error":"conflict","from":"b/00","step":3,"to":"out/00"}"#,
+            forward.to_json().to_string()
+        );
+
+        let reverse = tracker.check_iteration(Reverse::new(from, to)).unwrap_err();
+        let conflict = MultimodeConflict::new(forward, reverse);
+        assert_eq!(
+            concat!(
+                r#"{"error":"conflict","#,
+                r#""forward":{"cause":"claimed","claimed_at":1,"claimed_by":"a/00","error":"conflict","from":"b/00","step":3,"to":"out/00"},"#,
+                r#""reverse":{"cause":"claimed","claimed_at":1,"claimed_by":"b/00","error":"conflict","from":"a/00","step":3,"to":"out/00"}}"#
+            ),
+            conflict.to_json().to_string()
+        );
+    }
+
     #[test]
     fn must_forward_rename() {
         let from = &["01", "02", "03"];
         let to = &["00", "01", "02"];
-        let mut tracker = DataTracker::new(from);
+        let mut tracker = DataTracker::new(from, false);
         assert!(tracker.check_iteration(Forward::new(from, to)).is_ok());
         assert!(tracker.check_iteration(Reverse::new(from, to)).is_err());
     }
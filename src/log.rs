@@ -0,0 +1,78 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use chrono::Local;
+
+use crate::iter::Operation;
+
+/// An append-only record of the operations attempted, one JSON object per line
+///
+/// Each record is written with a single call, so logs shared between concurrent runs keep whole
+/// lines. Without a path, records are discarded.
+pub struct Log {
+    file: Option<fs::File>,
+    mode: &'static str,
+}
+
+impl Log {
+    pub fn open(path: Option<&Path>, mode: &'static str) -> io::Result<Self> {
+        let file = match path {
+            Some(path) => Some(fs::File::options().create(true).append(true).open(path)?),
+            None => None,
+        };
+        Ok(Self { file, mode })
+    }
+
+    /// Record the outcome of an operation, e.g. "ok" or "failed: permission denied"
+    pub fn record(&mut self, op: &Operation<'_>, result: &str) -> io::Result<()> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+
+        let record = serde_json::json!({
+            "time": Local::now().to_rfc3339(),
+            "mode": self.mode,
+            "from": op.from.to_string_lossy(),
+            "to": op.to.to_string_lossy(),
+            "result": result,
+        });
+        file.write_all(format!("{}\n", record).as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use crate::iter::Operation;
+
+    #[test]
+    fn records_are_appended() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mmv.log");
+        let op = Operation {
+            from: Path::new("a.txt"),
+            to: Path::new("b.txt"),
+        };
+
+        for result in ["ok", "failed: not found"] {
+            let mut log = super::Log::open(Some(&path), "move").unwrap();
+            log.record(&op, result).unwrap();
+        }
+
+        let content = fs::read_to_string(&path).unwrap();
+        let records: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(2, records.len());
+        assert_eq!("move", records[0]["mode"]);
+        assert_eq!("a.txt", records[0]["from"]);
+        assert_eq!("b.txt", records[0]["to"]);
+        assert_eq!("failed: not found", records[1]["result"]);
+    }
+}
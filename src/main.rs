@@ -1,63 +1,750 @@
 use std::{
-    fs,
+    fmt, fs,
     io::{self, Write},
+    ops::Range,
     path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
 };
 
 mod args;
+mod audio;
 mod iter;
+mod log;
+mod map;
 mod paths;
 mod rename;
+mod script;
+mod tags;
 mod template;
 
-use args::{Args, ExecutionMode, SortMode};
+use anyhow::Context;
+use args::{Args, ErrorFormat, ExecutionMode, OnEmpty, OnExecFailure, SortMode, StrategyMode};
 use either::Either;
-use iter::{Forward, Operation, Reverse};
-use rename::Renamer;
+use iter::{Conflict, Forward, Operation, Reverse, Strategy};
+use log::Log;
+use rename::{RenameError, Renamer};
 
 use crate::iter::{DataTracker, MultimodeConflict};
 
 fn main() {
-    if let Err(e) = run(&mut Args::parse()) {
-        eprintln!("{}", e);
+    let mut opts = Args::parse();
+    if let Err(e) = run(&mut opts) {
+        match opts.error_format {
+            ErrorFormat::Text => eprintln!("{}", e),
+            ErrorFormat::Json => eprintln!("{}", error_json(&e)),
+        }
         std::process::exit(1);
     }
 }
 
+/// Describe an error as a JSON object, with conflicts broken down into their parts
+fn error_json(error: &anyhow::Error) -> serde_json::Value {
+    if let Some(conflict) = error.downcast_ref::<Conflict>() {
+        return conflict.to_json();
+    }
+    if let Some(conflict) = error.downcast_ref::<MultimodeConflict>() {
+        return conflict.to_json();
+    }
+    serde_json::json!({ "error": "other", "message": error.to_string() })
+}
+
 fn run(opts: &mut Args) -> anyhow::Result<()> {
-    let paths = opts.paths.iter().flat_map(paths::extract);
-    let from = sort_paths(opts.sort, paths)?;
-    let mut renamer = Renamer::new(opts, Some(from.len()));
-    let to: Vec<_> = from.iter().map(|x| renamer.rename(x)).collect();
-    let operations = select_iteration_mode(&from, &to)?;
+    let started = Instant::now();
+
+    if opts.count_only {
+        println!("{}", collect_paths(opts)?.len());
+        return Ok(());
+    }
+
+    let (mut from, mut to) = match opts.map.clone() {
+        Some(path) => read_map(&path)?,
+        None => render_paths(opts)?,
+    };
+    check_nesting(opts, &from, &to)?;
+    let skipped = if opts.interactive {
+        let mut input = io::stdin().lock();
+        resolve_conflicts(&mut from, &mut to, opts, |conflict| {
+            ask_to_skip(&mut input, &mut io::stderr(), conflict)
+        })?
+    } else if opts.quiet_conflicts && matches!(opts.execution, ExecutionMode::Preview) {
+        resolve_conflicts(&mut from, &mut to, opts, |_| Ok(true))?
+    } else {
+        Vec::new()
+    };
+    let mut plan = select_iteration_mode(&from, &to, opts)?;
+    plan.skipped = skipped;
+
+    if plan.strategy == Strategy::Stage {
+        if opts.emit_script.is_some() {
+            anyhow::bail!("--emit-script does not support --strategy stage");
+        }
+        if matches!(opts.execution, ExecutionMode::Copy) {
+            anyhow::bail!("--strategy stage applies only to moves");
+        }
+    }
+
+    if let Some(path) = &opts.emit_script {
+        return if path.as_os_str() == "-" {
+            let mut handle = io::BufWriter::new(io::stdout().lock());
+            emit_script(&mut handle, opts, plan.operations)?;
+            Ok(handle.flush()?)
+        } else {
+            fs::File::create(path)
+                .and_then(|file| {
+                    let mut file = io::BufWriter::new(file);
+                    emit_script(&mut file, opts, plan.operations)?;
+                    file.flush()
+                })
+                .with_context(|| format!("unable to write script: {}", path.display()))
+        };
+    }
+
+    if matches!(opts.execution, ExecutionMode::Move) && !opts.yes {
+        let mut input = io::stdin().lock();
+        if !confirm(&mut input, &mut io::stderr(), opts, &from, &to)? {
+            anyhow::bail!("cancelled; no files were moved");
+        }
+    }
+
+    if matches!(opts.execution, ExecutionMode::Move) {
+        check_backups(opts, &from, &to)?;
+    }
+
+    if opts.check_permissions {
+        let mut handle = io::stdout().lock();
+        let problems = check_permissions(&mut handle, opts.execution, &from, &to)?;
+        if problems > 0 {
+            anyhow::bail!("permission check found {} problems", problems);
+        }
+    }
+
+    if matches!(opts.execution, ExecutionMode::Copy) {
+        check_overwrites(opts, &from, &to)?;
+        check_free_space(opts, &from, &to)?;
+    }
+
+    if !matches!(opts.execution, ExecutionMode::Preview) {
+        create_target_dirs(opts, &to)?;
+    }
+
+    if let Some(dir) = &opts.temp_dir {
+        check_writable(dir)
+            .with_context(|| format!("temp dir is not writable: {}", dir.display()))?;
+    }
+
+    // Buffered for speed, but flushed every so often so that long runs stream into pagers
+    let mut handle = io::BufWriter::new(io::stdout().lock());
+
+    let summary = match opts.execution {
+        ExecutionMode::Copy => do_copy(&mut handle, opts, plan.operations)?,
+        ExecutionMode::Move if plan.strategy == Strategy::Stage => {
+            do_staged_rename(&mut handle, opts, plan.operations)?
+        }
+        ExecutionMode::Move => do_rename(&mut handle, opts, plan.operations)?,
+        ExecutionMode::Preview => preview(&mut handle, opts, plan)?,
+    };
+
+    if opts.time && !matches!(opts.execution, ExecutionMode::Preview) {
+        writeln!(
+            handle,
+            "{}",
+            format_throughput(summary.count, started.elapsed())
+        )?;
+    }
+
+    // Only operations which were carried out are mapped, so that the map can undo a partial batch
+    if let Some(path) = &opts.write_map {
+        if !matches!(opts.execution, ExecutionMode::Preview) {
+            let (done_from, done_to): (Vec<_>, Vec<_>) = summary.completed.iter().cloned().unzip();
+            map::write(path, &done_from, &done_to)
+                .with_context(|| format!("unable to write map: {}", path.display()))?;
+        }
+    }
+
+    // Failures are reported whatever the verbosity, on stderr, before giving up
+    if !summary.failures.is_empty() {
+        handle.flush()?;
+        summary.write_failures(&mut io::stderr())?;
+        match summary.failures.len() {
+            1 => anyhow::bail!("1 operation failed"),
+            count => anyhow::bail!("{} operations failed", count),
+        }
+    }
+
+    if opts.verify && !matches!(opts.execution, ExecutionMode::Preview) {
+        let problems = verify(&mut handle, opts.execution, &from, &to)?;
+        if problems > 0 {
+            anyhow::bail!("verification failed with {} problems", problems);
+        }
+    }
+
+    handle.flush()?;
+    Ok(())
+}
+
+/// Write the plan as a shell script rather than carrying it out
+///
+/// The script creates target directories wherever the run itself would.
+fn emit_script<'a>(
+    writer: &mut impl Write,
+    opts: &Args,
+    operations: impl Iterator<Item = Operation<'a>>,
+) -> io::Result<()> {
+    let command = match opts.execution {
+        ExecutionMode::Copy => "cp",
+        _ => "mv",
+    };
+    let create_dirs = opts.mkdirs || opts.parents || opts.out_dir.is_some();
+    script::write(writer, command, create_dirs, operations)
+}
+
+/// Create the output directory and the --backup-dir, along with the directory of every target
+/// under --parents or --mkdirs
+fn create_target_dirs(opts: &Args, to: &[PathBuf]) -> io::Result<()> {
+    if let Some(dir) = &opts.out_dir {
+        fs::create_dir_all(dir)?;
+    }
+
+    let parents: std::collections::BTreeSet<_> = to
+        .iter()
+        .filter_map(|path| path.parent())
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .collect();
+    for parent in parents {
+        if opts.parents || opts.mkdirs {
+            fs::create_dir_all(parent)?;
+        } else if !parent.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "target directory does not exist (pass --mkdirs to create it)\n  {}",
+                    parent.display()
+                ),
+            ));
+        }
+    }
+
+    if let Some(dir) = &opts.backup_dir {
+        fs::create_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+/// Before copying, make sure each destination filesystem has room for the files bound for it
+///
+/// With --min-free-space, that much must also be left over afterwards.
+fn check_free_space(opts: &Args, from: &[PathBuf], to: &[PathBuf]) -> anyhow::Result<()> {
+    use std::collections::HashMap;
+
+    let mut required = HashMap::new();
+    for (from, to) in from.iter().zip(to).filter(|(from, to)| from != to) {
+        // Directories yet to be created count against the filesystem of their nearest existing ancestor
+        let dir = to
+            .parent()
+            .unwrap_or(Path::new(""))
+            .ancestors()
+            .find(|dir| dir.is_dir())
+            .unwrap_or(Path::new("."));
+        let size = fs::metadata(from)
+            .with_context(|| format!("unable to read metadata: {}", from.display()))?
+            .len();
+        let filesystem = filesystem(dir)
+            .with_context(|| format!("unable to read metadata: {}", dir.display()))?;
+        required.entry(filesystem).or_insert((dir, 0)).1 += size;
+    }
+
+    for (dir, bytes) in required.into_values() {
+        let available = fs2::available_space(dir)
+            .with_context(|| format!("unable to check free space: {}", dir.display()))?;
+        if let Some(shortfall) = space_shortfall(bytes, available, opts.min_free_space) {
+            anyhow::bail!(
+                "not enough free space to copy {} ({} available, {} short)\n  {}",
+                format_bytes(bytes),
+                format_bytes(available),
+                format_bytes(shortfall),
+                dir.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// How many more bytes are needed to write `required` bytes and still leave `margin` free
+fn space_shortfall(required: u64, available: u64, margin: u64) -> Option<u64> {
+    let needed = required.saturating_add(margin);
+    (needed > available).then(|| needed - available)
+}
+
+/// Identifies the filesystem a directory is on, so that files bound for it can be counted together
+#[cfg(unix)]
+fn filesystem(dir: &Path) -> io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(fs::metadata(dir)?.dev())
+}
+
+#[cfg(not(unix))]
+fn filesystem(dir: &Path) -> io::Result<PathBuf> {
+    Ok(dir.into())
+}
+
+/// Re-check the filesystem after executing a batch, returning the number of problems found
+///
+/// Every target must exist. After a move, every source which is not also a target must be gone.
+fn verify<P: AsRef<Path>>(
+    writer: &mut impl Write,
+    mode: ExecutionMode,
+    from: &[P],
+    to: &[P],
+) -> io::Result<usize> {
+    use std::collections::HashSet;
+
+    let targets: HashSet<_> = to.iter().map(|path| path.as_ref()).collect();
+    let mut problems = 0;
+
+    for path in to.iter().map(|path| path.as_ref()) {
+        if let Err(e) = fs::metadata(path) {
+            writeln!(writer, "missing target: {}: {}", path.display(), e)?;
+            problems += 1;
+        }
+    }
+
+    if let ExecutionMode::Move = mode {
+        for path in from.iter().map(|path| path.as_ref()) {
+            if !targets.contains(path) && fs::symlink_metadata(path).is_ok() {
+                writeln!(writer, "source still exists: {}", path.display())?;
+                problems += 1;
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Report sources which cannot be read and target directories which cannot be written to
+///
+/// Target directories which do not exist yet are checked at their nearest existing ancestor,
+/// where they would be created. Unless copying, the directories of the sources must be writable
+/// too, since moving a file removes it from its directory.
+fn check_permissions<P: AsRef<Path>>(
+    writer: &mut impl Write,
+    mode: ExecutionMode,
+    from: &[P],
+    to: &[P],
+) -> io::Result<usize> {
+    use std::collections::BTreeSet;
+
+    let mut problems = 0;
+    let or_current = |dir: &Path| -> PathBuf {
+        if dir.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            dir.into()
+        }
+    };
+
+    for path in from.iter().map(|path| path.as_ref()) {
+        let readable = if path.is_dir() {
+            fs::read_dir(path).map(drop)
+        } else {
+            fs::File::open(path).map(drop)
+        };
+        if let Err(e) = readable {
+            writeln!(writer, "unreadable source: {}: {}", path.display(), e)?;
+            problems += 1;
+        }
+    }
+
+    if !matches!(mode, ExecutionMode::Copy) {
+        let dirs: BTreeSet<_> = from
+            .iter()
+            .map(|path| or_current(path.as_ref().parent().unwrap_or(Path::new(""))))
+            .collect();
+        for dir in dirs {
+            if let Err(e) = check_writable(&dir) {
+                writeln!(
+                    writer,
+                    "unwritable source directory: {}: {}",
+                    dir.display(),
+                    e
+                )?;
+                problems += 1;
+            }
+        }
+    }
+
+    let dirs: BTreeSet<_> = to
+        .iter()
+        .map(|path| {
+            let dir = path.as_ref().parent().unwrap_or(Path::new(""));
+            or_current(
+                dir.ancestors()
+                    .find(|dir| dir.is_dir())
+                    .unwrap_or(Path::new("")),
+            )
+        })
+        .collect();
+    for dir in dirs {
+        if let Err(e) = check_writable(&dir) {
+            writeln!(
+                writer,
+                "unwritable target directory: {}: {}",
+                dir.display(),
+                e
+            )?;
+            problems += 1;
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Expand the input arguments into a sorted list of files
+fn collect_paths(opts: &Args) -> io::Result<Vec<PathBuf>> {
+    let listed = match &opts.from_file0 {
+        Some(path) => paths::read_list0(path)?,
+        None => Vec::new(),
+    };
+
+    let mut paths = Vec::new();
+    for path in opts.paths.iter().chain(&listed) {
+        let count = paths.len();
+        paths.extend(paths::extract(path, opts));
+        if paths.len() == count {
+            if opts.strict {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no files matched: {path}"),
+                ));
+            }
+            eprintln!("warning: no files matched: {path}");
+        }
+    }
+    sort_paths(opts.sort, paths.into_iter())
+}
+
+/// Collect and sort the input paths, then render a new name for each
+fn render_paths(opts: &mut Args) -> anyhow::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    if let Some(path) = &opts.template_file {
+        opts.template = read_template(path)
+            .with_context(|| format!("unable to read template: {}", path.display()))?;
+    }
+
+    let mut from = collect_paths(opts)?;
+    if let Some(dir) = &opts.order_by {
+        let reference = sort_paths(opts.sort, paths::extract(dir.to_string_lossy(), opts))?;
+        from = order_by_reference(from, &reference);
+    }
+
+    let mut count = from.len();
+    if let Some(range) = opts.range.clone() {
+        let (selected, offset) = select_range(from, range);
+        from = selected;
+        if opts.range_absolute {
+            opts.start = u32::try_from(offset)
+                .ok()
+                .and_then(|offset| opts.start.checked_add(offset))
+                .context("--range starts beyond the largest possible number")?;
+            // Pad to the last number of the whole set, so that every slice lines up
+            count -= offset;
+        } else {
+            count = from.len();
+        }
+    }
+
+    let mut renamer = Renamer::new(opts, Some(count))?;
+    if opts.on_empty == OnEmpty::Skip {
+        from.retain(|path| {
+            let empty = renamer.empty_capture(path);
+            if empty {
+                eprintln!(
+                    "warning: skipping file whose name the pattern leaves empty: {}",
+                    path.display()
+                );
+            }
+            !empty
+        });
+    }
+    if let Some(position) = opts.insert_at {
+        from = renamer.insert_at(from, position);
+    }
+    renamer.scan(&from)?;
+    let mut to = Vec::with_capacity(from.len());
+    let mut named = Vec::with_capacity(from.len());
+    for path in from {
+        match renamer.rename(&path) {
+            Ok(target) => {
+                named.push(path);
+                to.push(target);
+            }
+            Err(e @ RenameError::Command { .. }) if opts.on_exec_failure == OnExecFailure::Skip => {
+                eprintln!("warning: skipping file: {}", e);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let from = named;
+    if opts.number_collisions {
+        rename::number_collisions(&mut to);
+    }
+    Ok((from, to))
+}
+
+/// Order files as their namesakes (by stem) are ordered in `reference`, with the rest last
+///
+/// Files without a namesake keep their relative order.
+fn order_by_reference(mut paths: Vec<PathBuf>, reference: &[PathBuf]) -> Vec<PathBuf> {
+    use std::collections::HashMap;
+
+    let mut positions = HashMap::new();
+    for (idx, path) in reference.iter().enumerate() {
+        if let Some(stem) = path.file_stem() {
+            positions.entry(stem).or_insert(idx);
+        }
+    }
+
+    paths.sort_by_key(|path| {
+        path.file_stem()
+            .and_then(|stem| positions.get(stem))
+            .copied()
+            .unwrap_or(usize::MAX)
+    });
+    paths
+}
+
+/// Keep only the files within `range`, returning them with the position of the first
+///
+/// A range extending past the last file is clamped to fit, with a warning.
+fn select_range(mut paths: Vec<PathBuf>, range: Range<usize>) -> (Vec<PathBuf>, usize) {
+    let len = paths.len();
+    let clamped = range.start.min(len)..range.end.min(len);
+    if clamped != range && range.end != usize::MAX {
+        eprintln!(
+            "warning: range {}..{} exceeds the {} files matched; using {}..{}",
+            range.start, range.end, len, clamped.start, clamped.end
+        );
+    }
+
+    paths.truncate(clamped.end);
+    paths.drain(..clamped.start);
+    (paths, clamped.start)
+}
+
+/// Read a template from a file, ignoring a single trailing line ending
+fn read_template(path: &Path) -> io::Result<String> {
+    let template = fs::read_to_string(path)?;
+    let template = template
+        .strip_suffix('\n')
+        .map(|s| s.strip_suffix('\r').unwrap_or(s))
+        .unwrap_or(&template);
+    Ok(template.into())
+}
+
+/// Read an explicit mapping of renames, warning about (and skipping) missing sources
+fn read_map(path: &Path) -> anyhow::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mapping =
+        map::read(path).with_context(|| format!("unable to read map: {}", path.display()))?;
+
+    for missing in &mapping.missing {
+        eprintln!("warning: skipping missing file: {}", missing.display());
+    }
+
+    Ok((mapping.from, mapping.to))
+}
 
-    match opts.execution {
-        ExecutionMode::Copy => do_copy(operations)?,
-        ExecutionMode::Move => do_rename(operations)?,
-        ExecutionMode::Preview => preview(operations)?,
+/// Refuse to move anything inside itself, or to gather files below a directory being walked
+///
+/// Targets under an input directory are fine in general, since files are renamed in place; only
+/// an --out-dir nested within an input is refused, as later runs would pick up its contents.
+fn check_nesting(opts: &Args, from: &[PathBuf], to: &[PathBuf]) -> anyhow::Result<()> {
+    for (from, to) in from.iter().zip(to) {
+        if from != to && to.starts_with(from) {
+            anyhow::bail!(
+                "cannot move a path inside itself\n  {}\n  {}",
+                from.display(),
+                to.display()
+            );
+        }
     }
 
+    let out_dir = match &opts.out_dir {
+        Some(dir) => std::path::absolute(dir)?,
+        None => return Ok(()),
+    };
+    for input in opts
+        .paths
+        .iter()
+        .map(Path::new)
+        .filter(|path| path.is_dir())
+    {
+        let input = std::path::absolute(input)?;
+        if out_dir != input && out_dir.starts_with(&input) {
+            anyhow::bail!(
+                "output directory is inside an input directory\n  {}\n  {}",
+                out_dir.display(),
+                input.display()
+            );
+        }
+    }
     Ok(())
 }
 
+/// Show the plan and ask whether to go ahead, warning prominently about large batches
+///
+/// Anything but "y" (including the end of the input) is a refusal.
+fn confirm(
+    input: &mut impl io::BufRead,
+    prompt: &mut impl Write,
+    opts: &Args,
+    from: &[PathBuf],
+    to: &[PathBuf],
+) -> io::Result<bool> {
+    // The prompt is written to stderr, which may go somewhere other than stdout
+    let color = opts.color_for(&io::stderr());
+    let mut count = 0;
+    for (from, to) in from.iter().zip(to).filter(|(from, to)| from != to) {
+        format_op(prompt, &Operation { from, to }, opts.width, color)?;
+        count += 1;
+    }
+
+    if count > opts.confirm_threshold {
+        let warning = format!("!! WARNING: {} files will be moved !!", count);
+        let rule = "!".repeat(warning.len());
+        writeln!(prompt, "\n{}\n{}\n{}\n", rule, warning, rule)?;
+    }
+
+    write!(prompt, "Move {} files? [y/N] ", count)?;
+    prompt.flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(answer.trim().eq_ignore_ascii_case("y"))
+}
+
+/// Remove operations which keep the batch from being planned, as `skip` agrees, until a plan
+/// succeeds, returning the operations removed
+///
+/// If `skip` declines every conflicting operation, the conflict is returned.
+fn resolve_conflicts(
+    from: &mut Vec<PathBuf>,
+    to: &mut Vec<PathBuf>,
+    opts: &Args,
+    mut skip: impl FnMut(&Conflict) -> io::Result<bool>,
+) -> anyhow::Result<Vec<(PathBuf, PathBuf)>> {
+    let mut removed = Vec::new();
+    loop {
+        let error = match select_iteration_mode(from, to, opts) {
+            Ok(_) => return Ok(removed),
+            Err(error) => error,
+        };
+
+        let conflicts = if let Some(conflict) = error.downcast_ref::<MultimodeConflict>() {
+            vec![&conflict.forward, &conflict.reverse]
+        } else if let Some(conflict) = error.downcast_ref::<Conflict>() {
+            vec![conflict]
+        } else {
+            return Err(error);
+        };
+
+        let mut skipped = None;
+        for conflict in conflicts {
+            if skip(conflict)? {
+                skipped = from.iter().position(|path| *path == conflict.from);
+                break;
+            }
+        }
+
+        match skipped {
+            Some(idx) => removed.push((from.remove(idx), to.remove(idx))),
+            None => return Err(error),
+        }
+    }
+}
+
+/// Ask whether to skip a conflicting operation
+///
+/// Anything but "y" (including the end of the input) is a refusal.
+fn ask_to_skip(
+    input: &mut impl io::BufRead,
+    prompt: &mut impl Write,
+    conflict: &Conflict,
+) -> io::Result<bool> {
+    write!(prompt, "{}Skip this operation? [y/N] ", conflict)?;
+    prompt.flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(answer.trim().eq_ignore_ascii_case("y"))
+}
+
+/// A batch of operations in the order they can safely be applied
+struct Plan<'a, P> {
+    strategy: Strategy,
+    operations: Either<Forward<'a, P>, Reverse<'a, P>>,
+
+    /// The conflict which ruled out forward iteration, if any
+    reason: Option<Conflict>,
+
+    /// Conflicting operations left out of the batch
+    skipped: Vec<(PathBuf, PathBuf)>,
+}
+
 fn select_iteration_mode<'a, P: AsRef<Path> + 'a>(
     from: &'a [P],
     to: &'a [P],
-) -> anyhow::Result<Either<Forward<'a, P>, Reverse<'a, P>>> {
-    let mut data = DataTracker::new(from);
+    opts: &Args,
+) -> anyhow::Result<Plan<'a, P>> {
+    let mut data = DataTracker::new(from, opts.fold_case);
+
+    if let StrategyMode::Stage = opts.strategy {
+        let mut iteration = Forward::new(from, to);
+        data.check_claims(&mut iteration)?;
+        iteration.reset();
+        return Ok(Plan {
+            strategy: Strategy::Stage,
+            operations: Either::Left(iteration),
+            reason: None,
+            skipped: Vec::new(),
+        });
+    }
+
+    if let StrategyMode::Reverse = opts.strategy {
+        let mut iteration = Reverse::new(from, to);
+        data.check_iteration(&mut iteration)?;
+        iteration.reset();
+        return Ok(Plan {
+            strategy: Strategy::Reverse,
+            operations: Either::Right(iteration),
+            reason: None,
+            skipped: Vec::new(),
+        });
+    }
 
     let mut iteration = Forward::new(from, to);
     let forward_iteration_result = data.check_iteration(&mut iteration);
+    if let (StrategyMode::Forward, Err(conflict)) = (opts.strategy, &forward_iteration_result) {
+        return Err(conflict.clone().into());
+    }
+
     if forward_iteration_result.is_ok() {
         iteration.reset();
-        return Ok(Either::Left(iteration));
+        return Ok(Plan {
+            strategy: Strategy::Forward,
+            operations: Either::Left(iteration),
+            reason: None,
+            skipped: Vec::new(),
+        });
     }
 
     let mut iteration = Reverse::new(from, to);
     let reverse_iteration_result = data.check_iteration(&mut iteration);
     if reverse_iteration_result.is_ok() {
         iteration.reset();
-        return Ok(Either::Right(iteration));
+        return Ok(Plan {
+            strategy: Strategy::Reverse,
+            operations: Either::Right(iteration),
+            reason: forward_iteration_result.err(),
+            skipped: Vec::new(),
+        });
     }
 
     Err(anyhow::anyhow!(MultimodeConflict::new(
@@ -66,91 +753,2508 @@ fn select_iteration_mode<'a, P: AsRef<Path> + 'a>(
     )))
 }
 
-fn do_copy<'a>(operations: impl Iterator<Item = Operation<'a>>) -> io::Result<()> {
-    let handle = io::stdout();
-    let mut handle = handle.lock();
-    let mut count = 0;
+/// Totals accumulated while executing a batch of operations.
+#[derive(Debug, Default)]
+struct Summary {
+    count: usize,
+    bytes: u64,
+    skipped: usize,
 
-    for op in operations {
-        fs::copy(op.from, op.to)?;
-        format_op(&mut handle, &op)?;
-        count += 1;
+    /// Operations whose source and target are the same path
+    unchanged: usize,
+
+    /// Sources copied aside before being moved, with --keep-original
+    originals: usize,
+    failures: Vec<Failure>,
+
+    /// Operations carried out, kept only for --write-map
+    completed: Vec<(PathBuf, PathBuf)>,
+}
+
+/// An operation which could not be completed
+#[derive(Debug)]
+struct Failure {
+    from: PathBuf,
+    to: PathBuf,
+    error: io::Error,
+}
+
+impl Summary {
+    fn complete(&mut self, op: &Operation<'_>, opts: &Args) {
+        if opts.write_map.is_some() {
+            self.completed.push((op.from.into(), op.to.into()));
+        }
     }
 
-    println!("Copied {} files", count);
-    Ok(())
+    fn fail(&mut self, op: &Operation<'_>, error: io::Error) {
+        self.failures.push(Failure {
+            from: op.from.into(),
+            to: op.to.into(),
+            error,
+        });
+    }
+
+    fn write_skipped(&self, writer: &mut impl Write) -> io::Result<()> {
+        if self.skipped > 0 {
+            write!(writer, ", skipped {}", self.skipped)?;
+        }
+        Ok(())
+    }
+
+    fn write_unchanged(&self, writer: &mut impl Write) -> io::Result<()> {
+        if self.unchanged > 0 {
+            write!(writer, ", {} unchanged", self.unchanged)?;
+        }
+        Ok(())
+    }
+
+    fn write_failures(&self, writer: &mut impl Write) -> io::Result<()> {
+        if self.failures.is_empty() {
+            return Ok(());
+        }
+
+        let count = self.failures.len();
+        let operations = if count == 1 {
+            "operation"
+        } else {
+            "operations"
+        };
+        writeln!(writer, "Failed {} {}:", count, operations)?;
+        for failure in &self.failures {
+            writeln!(
+                writer,
+                "  {} -> {}: {}",
+                failure.from.display(),
+                failure.to.display(),
+                failure.error
+            )?;
+        }
+        Ok(())
+    }
 }
 
-fn do_rename<'a>(operations: impl Iterator<Item = Operation<'a>>) -> io::Result<()> {
-    let handle = io::stdout();
-    let mut handle = handle.lock();
-    let mut count = 0;
+fn do_copy<'a>(
+    writer: &mut impl Write,
+    opts: &Args,
+    operations: impl Iterator<Item = Operation<'a>>,
+) -> io::Result<Summary> {
+    let mut summary = Summary::default();
+    let color = opts.color_for(&io::stdout());
+    let mut completed = Vec::new();
+    let mut log = Log::open(opts.log.as_deref(), "copy")?;
 
     for op in operations {
-        fs::rename(op.from, op.to)?;
-        format_op(&mut handle, &op)?;
-        count += 1;
+        if op.from == op.to {
+            summary.unchanged += 1;
+            continue;
+        }
+
+        let result = copy_file(&op, opts);
+        match &result {
+            Ok(Ok(_)) => log.record(&op, "ok")?,
+            Ok(Err(skip)) => log.record(&op, &format!("skipped: {}", skip))?,
+            Err(e) => log.record(&op, &format!("failed: {}", e))?,
+        }
+
+        match result {
+            Ok(Ok(bytes)) => {
+                if opts.show_operations() {
+                    format_op(writer, &op, opts.width, color)?;
+                }
+                summary.complete(&op, opts);
+                if opts.atomic {
+                    completed.push(op);
+                }
+                summary.count += 1;
+                summary.bytes += bytes;
+                flush_periodically(writer, summary.count)?;
+            }
+            Ok(Err(skip)) => {
+                if opts.show_operations() {
+                    writeln!(writer, "{} (skipped, {})", op.to.display(), skip)?;
+                }
+                summary.skipped += 1;
+            }
+            Err(e) if opts.continue_on_error => summary.fail(&op, e),
+            Err(e) if opts.atomic => {
+                return Err(roll_back(&completed, e, |op| fs::remove_file(op.to)))
+            }
+            Err(e) => return Err(e),
+        }
     }
 
-    println!("Moved {} files", count);
-    Ok(())
+    if opts.show_summary() {
+        write!(
+            writer,
+            "Copied {} files ({})",
+            summary.count,
+            format_bytes(summary.bytes)
+        )?;
+        summary.write_skipped(writer)?;
+        summary.write_unchanged(writer)?;
+        writeln!(writer)?;
+    }
+    Ok(summary)
 }
 
-fn preview<'a>(operations: impl Iterator<Item = Operation<'a>>) -> io::Result<()> {
-    let handle = io::stdout();
-    let mut handle = handle.lock();
-    let mut count = 0;
+/// The reason a copy was skipped
+#[derive(Clone, Copy, Debug)]
+enum Skip {
+    Identical,
+    NotNewer,
+}
 
-    for op in operations {
-        format_op(&mut handle, &op)?;
-        count += 1;
+impl fmt::Display for Skip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Skip::Identical => f.write_str("identical"),
+            Skip::NotNewer => f.write_str("destination is not older"),
+        }
+    }
+}
+
+/// Copy a single file, returning the number of bytes copied or the reason the copy was skipped
+fn copy_file(op: &Operation<'_>, opts: &Args) -> io::Result<Result<u64, Skip>> {
+    match skip_copy(op, opts)? {
+        Some(skip) => Ok(Err(skip)),
+        None => fs::copy(op.from, op.to).map(Ok),
+    }
+}
+
+/// The reason a copy would be skipped, if any
+fn skip_copy(op: &Operation<'_>, opts: &Args) -> io::Result<Option<Skip>> {
+    if opts.update && !is_newer(op.from, op.to)? {
+        return Ok(Some(Skip::NotNewer));
+    }
+    if opts.skip_identical && is_identical(op.from, op.to)? {
+        return Ok(Some(Skip::Identical));
+    }
+    Ok(None)
+}
+
+/// With --atomic, make sure that no copy would overwrite an existing file, which a rollback
+/// could not restore
+fn check_overwrites(opts: &Args, from: &[PathBuf], to: &[PathBuf]) -> io::Result<()> {
+    if !opts.atomic {
+        return Ok(());
     }
 
-    println!("Would rename {} files", count);
+    for (from, to) in from.iter().zip(to).filter(|(from, to)| from != to) {
+        let op = Operation { from, to };
+        if fs::symlink_metadata(to).is_ok() && skip_copy(&op, opts)?.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "--atomic cannot undo a copy over an existing file: {}",
+                    to.display()
+                ),
+            ));
+        }
+    }
     Ok(())
 }
 
-fn format_op(writer: &mut io::StdoutLock, op: &Operation<'_>) -> io::Result<()> {
-    const MAX_FORMATTED_LEN: usize = 80;
+/// True if `a` was modified more recently than `b`, or if `b` does not exist
+fn is_newer(a: &Path, b: &Path) -> io::Result<bool> {
+    let b_modified = match b.metadata() {
+        Ok(meta) => meta.modified()?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e),
+    };
+    Ok(a.metadata()?.modified()? > b_modified)
+}
+
+/// Compare two files by size and, if sizes match, by a hash of their content
+fn is_identical(a: &Path, b: &Path) -> io::Result<bool> {
+    use std::{collections::hash_map::DefaultHasher, hash::Hasher, io::Read};
 
-    let formatted = format!("{} -> {}", op.from.display(), op.to.display());
-    if formatted.len() > MAX_FORMATTED_LEN {
-        writeln!(writer, "{}\n -> {}", op.from.display(), op.to.display())
-    } else {
-        writeln!(writer, "{}", formatted)
+    fn hash_file(path: &Path) -> io::Result<u64> {
+        let mut file = fs::File::open(path)?;
+        let mut hasher = DefaultHasher::new();
+        let mut buf = [0; 8192];
+
+        loop {
+            match file.read(&mut buf)? {
+                0 => return Ok(hasher.finish()),
+                n => hasher.write(&buf[..n]),
+            }
+        }
+    }
+
+    let b_len = match b.metadata() {
+        Ok(meta) => meta.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+
+    if a.metadata()?.len() != b_len {
+        return Ok(false);
     }
+
+    Ok(hash_file(a)? == hash_file(b)?)
 }
 
-fn sort_paths(sort: SortMode, paths: impl Iterator<Item = PathBuf>) -> io::Result<Vec<PathBuf>> {
-    use std::fs::Metadata;
-    use std::time::SystemTime;
+fn do_rename<'a>(
+    writer: &mut impl Write,
+    opts: &Args,
+    operations: impl Iterator<Item = Operation<'a>>,
+) -> io::Result<Summary> {
+    use std::collections::HashSet;
+
+    let mut summary = Summary::default();
+    let color = opts.color_for(&io::stdout());
+    let mut completed = Vec::new();
+    let mut log = Log::open(opts.log.as_deref(), "move")?;
+
+    // Sources left in place by a failure, which later operations must not overwrite
+    let mut stranded = HashSet::new();
+
+    for op in operations {
+        if op.from == op.to {
+            summary.unchanged += 1;
+            continue;
+        }
+
+        let result = if stranded.contains(op.to) {
+            Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} failed to move out of the way", op.to.display()),
+            ))
+        } else {
+            keep_and_move(&op, opts)
+        };
+        match &result {
+            Ok(_) => log.record(&op, "ok")?,
+            Err(e) => log.record(&op, &format!("failed: {}", e))?,
+        }
 
-    fn collect_with_meta(
-        paths: impl Iterator<Item = PathBuf>,
-        extractor: impl Fn(Metadata) -> io::Result<SystemTime>,
-    ) -> io::Result<Vec<(PathBuf, SystemTime)>> {
-        paths
-            .map(|x| x.metadata().and_then(&extractor).map(|y| (x, y)))
-            .collect()
+        match result {
+            Ok(bytes) => {
+                if opts.show_operations() {
+                    format_op(writer, &op, opts.width, color)?;
+                }
+                summary.complete(&op, opts);
+                if opts.atomic {
+                    completed.push(op);
+                }
+                if opts.keep_original {
+                    summary.originals += 1;
+                }
+                summary.count += 1;
+                summary.bytes += bytes;
+                flush_periodically(writer, summary.count)?;
+            }
+            Err(e) if opts.continue_on_error => {
+                stranded.insert(op.from);
+                summary.fail(&op, e);
+            }
+            Err(e) if opts.atomic => {
+                return Err(roll_back(&completed, e, |op| {
+                    let undo = Operation {
+                        from: op.to,
+                        to: op.from,
+                    };
+                    move_file(&undo, opts).and_then(|_| discard_original(undo.to, opts))
+                }))
+            }
+            Err(e) => return Err(e),
+        }
     }
 
-    match sort {
-        SortMode::Created => {
-            let mut with_meta = collect_with_meta(paths, |x| x.created())?;
-            with_meta.sort_unstable_by_key(|x| x.1);
-            Ok(with_meta.into_iter().map(|x| x.0).collect())
+    if opts.show_summary() {
+        write!(
+            writer,
+            "Moved {} files ({})",
+            summary.count,
+            format_bytes(summary.bytes)
+        )?;
+        summary.write_unchanged(writer)?;
+        if summary.originals > 0 {
+            write!(writer, ", kept {} originals", summary.originals)?;
+        }
+        writeln!(writer)?;
+    }
+    Ok(summary)
+}
+
+/// Move every file to a temporary name beside its target, then from there to the target
+///
+/// This takes twice as many moves as a single pass, but no file can be in another's way. If a move
+/// fails, files are returned to where they started, since the temporary names mean nothing; with
+/// --continue-on-error, a file which cannot be put in place is left at its temporary name instead,
+/// and one whose target is a source that could not be staged goes back to where it started.
+fn do_staged_rename<'a>(
+    writer: &mut impl Write,
+    opts: &Args,
+    operations: impl Iterator<Item = Operation<'a>>,
+) -> io::Result<Summary> {
+    use std::collections::HashSet;
+
+    let mut summary = Summary::default();
+    let color = opts.color_for(&io::stdout());
+    let mut log = Log::open(opts.log.as_deref(), "move")?;
+    let mut staged = Vec::new();
+    let mut serial = 0;
+
+    // Sources which could not be staged, and so are still in the way of whatever targets them
+    let mut stranded = HashSet::new();
+
+    for op in operations {
+        if op.from == op.to {
+            summary.unchanged += 1;
+            continue;
         }
 
-        SortMode::Modified => {
-            let mut with_meta = collect_with_meta(paths, |x| x.modified())?;
-            with_meta.sort_unstable_by_key(|x| x.1);
-            Ok(with_meta.into_iter().map(|x| x.0).collect())
+        let temp = holding_path(op.to, &mut serial);
+        let stage = Operation {
+            from: op.from,
+            to: &temp,
+        };
+        match keep_and_move(&stage, opts) {
+            Ok(bytes) => staged.push((op, temp, bytes)),
+            Err(e) => {
+                log.record(&op, &format!("failed: {}", e))?;
+                if opts.continue_on_error {
+                    stranded.insert(op.from);
+                    summary.fail(&op, e);
+                } else {
+                    return Err(unstage(&staged, 0, e, opts));
+                }
+            }
         }
+    }
 
-        SortMode::Path => {
-            let mut paths: Vec<_> = paths.collect();
-            paths.sort_unstable();
-            Ok(paths)
+    // A file which cannot be placed goes back to its source, which then blocks its own placement
+    loop {
+        let before = stranded.len();
+        for (op, _, _) in &staged {
+            if stranded.contains(op.to) {
+                stranded.insert(op.from);
+            }
         }
+        if stranded.len() == before {
+            break;
+        }
+    }
+
+    for (placed, (op, temp, bytes)) in staged.iter().enumerate() {
+        if stranded.contains(op.from) {
+            let undo = Operation {
+                from: temp,
+                to: op.from,
+            };
+            let e = match move_file(&undo, opts).and_then(|_| discard_original(op.from, opts)) {
+                Ok(()) => io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} failed to move out of the way", op.to.display()),
+                ),
+                Err(e) => io::Error::new(e.kind(), format!("{}; left at {}", e, temp.display())),
+            };
+            log.record(op, &format!("failed: {}", e))?;
+            summary.fail(op, e);
+            continue;
+        }
+
+        let place = Operation {
+            from: temp,
+            to: op.to,
+        };
+        match move_file(&place, opts) {
+            Ok(_) => {
+                log.record(op, "ok")?;
+                if opts.show_operations() {
+                    format_op(writer, op, opts.width, color)?;
+                }
+                summary.complete(op, opts);
+                if opts.keep_original {
+                    summary.originals += 1;
+                }
+                summary.count += 1;
+                summary.bytes += bytes;
+                flush_periodically(writer, summary.count)?;
+            }
+            Err(e) => {
+                log.record(op, &format!("failed: {}", e))?;
+                if opts.continue_on_error {
+                    let message = format!("{}; left at {}", e, temp.display());
+                    summary.fail(op, io::Error::new(e.kind(), message));
+                } else {
+                    return Err(unstage(&staged, placed, e, opts));
+                }
+            }
+        }
+    }
+
+    if opts.show_summary() {
+        write!(
+            writer,
+            "Moved {} files ({}) by way of temporary names",
+            summary.count,
+            format_bytes(summary.bytes)
+        )?;
+        summary.write_unchanged(writer)?;
+        if summary.originals > 0 {
+            write!(writer, ", kept {} originals", summary.originals)?;
+        }
+        writeln!(writer)?;
+    }
+    Ok(summary)
+}
+
+/// A name beside `target` which no file has, for a file to wait under during a staged rename
+fn holding_path(target: &Path, serial: &mut usize) -> PathBuf {
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    loop {
+        *serial += 1;
+        let holding = format!(".{}.{}.{}.mmv-stage", name, std::process::id(), serial);
+        let candidate = target.with_file_name(holding);
+        if fs::symlink_metadata(&candidate).is_err() {
+            return candidate;
+        }
+    }
+}
+
+/// Return staged files to their sources after `error`, the first `placed` having reached their targets
+fn unstage(
+    staged: &[(Operation<'_>, PathBuf, u64)],
+    placed: usize,
+    error: io::Error,
+    opts: &Args,
+) -> io::Error {
+    let stages = staged.iter().map(|(op, temp, _)| Operation {
+        from: op.from,
+        to: temp,
+    });
+    let placements = staged[..placed].iter().map(|(op, temp, _)| Operation {
+        from: temp,
+        to: op.to,
+    });
+    let completed: Vec<_> = stages.chain(placements).collect();
+
+    roll_back(&completed, error, |op| {
+        let undo = Operation {
+            from: op.to,
+            to: op.from,
+        };
+        move_file(&undo, opts).and_then(|_| discard_original(undo.to, opts))
+    })
+}
+
+/// With --keep-original, copy a file before it is moved
+///
+/// The copy is named `<name>.orig` alongside the file, or keeps its name inside --backup-dir.
+/// An existing file is never overwritten.
+fn keep_original(path: &Path, opts: &Args) -> io::Result<()> {
+    if !opts.keep_original {
+        return Ok(());
+    }
+
+    let mut source = fs::File::open(path)?;
+    let mut target = fs::File::options()
+        .write(true)
+        .create_new(true)
+        .open(backup_path(path, opts))?;
+    io::copy(&mut source, &mut target)?;
+    Ok(())
+}
+
+/// Remove the copy kept by --keep-original, once the file it was made from is back in place
+fn discard_original(path: &Path, opts: &Args) -> io::Result<()> {
+    if !opts.keep_original {
+        return Ok(());
+    }
+
+    match fs::remove_file(backup_path(path, opts)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Where --keep-original puts the copy of a file
+fn backup_path(path: &Path, opts: &Args) -> PathBuf {
+    let name = path.file_name().expect("Must be a filename");
+    match &opts.backup_dir {
+        Some(dir) => dir.join(name),
+        None => {
+            let mut name = name.to_os_string();
+            name.push(".orig");
+            path.with_file_name(name)
+        }
+    }
+}
+
+/// Before moving, make sure that no two files would be kept under one name, and that no kept copy
+/// would land on an existing file
+fn check_backups(opts: &Args, from: &[PathBuf], to: &[PathBuf]) -> io::Result<()> {
+    use std::collections::HashMap;
+
+    if !opts.keep_original {
+        return Ok(());
+    }
+
+    let mut backups = HashMap::new();
+    for (from, _) in from.iter().zip(to).filter(|(from, to)| from != to) {
+        let backup = backup_path(from, opts);
+        if fs::symlink_metadata(&backup).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "a kept original would overwrite an existing file: {}",
+                    backup.display()
+                ),
+            ));
+        }
+        if let Some(other) = backups.insert(backup.clone(), from) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "originals of {} and {} would both be kept as {}",
+                    other.display(),
+                    from.display(),
+                    backup.display()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Move a file, keeping a copy of it first with --keep-original
+///
+/// If the move fails, the copy is removed again, since the file is still where it was.
+fn keep_and_move(op: &Operation<'_>, opts: &Args) -> io::Result<u64> {
+    keep_original(op.from, opts)?;
+    move_file(op, opts).inspect_err(|_| {
+        let _ = discard_original(op.from, opts);
+    })
+}
+
+/// Move a single file, returning its size
+fn move_file(op: &Operation<'_>, opts: &Args) -> io::Result<u64> {
+    let len = op.from.metadata()?.len();
+    match fs::rename(op.from, op.to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            move_across_devices(op.from, op.to, opts.temp_dir.as_deref())?
+        }
+        result => result?,
+    }
+    Ok(len)
+}
+
+/// Undo completed operations in reverse order after `error` interrupted a batch
+///
+/// Returns the error to be reported, noting whether the rollback itself succeeded.
+fn roll_back(
+    completed: &[Operation<'_>],
+    error: io::Error,
+    undo: impl Fn(&Operation<'_>) -> io::Result<()>,
+) -> io::Error {
+    for op in completed.iter().rev() {
+        if let Err(e) = undo(op) {
+            return io::Error::new(
+                error.kind(),
+                format!(
+                    "{}; rollback failed at {} -> {}: {}",
+                    error,
+                    op.from.display(),
+                    op.to.display(),
+                    e
+                ),
+            );
+        }
+    }
+
+    io::Error::new(
+        error.kind(),
+        format!("{}; rolled back {} operations", error, completed.len()),
+    )
+}
+
+/// Move a file between devices by copying it to a staging file and renaming that into place
+///
+/// The source is removed only once the destination is complete.
+fn move_across_devices(from: &Path, to: &Path, temp_dir: Option<&Path>) -> io::Result<()> {
+    let staging = staging_path(temp_dir, to);
+    fs::copy(from, &staging)?;
+
+    let result = match fs::rename(&staging, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(&staging, to).and_then(|_| fs::remove_file(&staging))
+        }
+        result => result,
+    };
+
+    if let Err(e) = result {
+        let _ = fs::remove_file(&staging);
+        return Err(e);
+    }
+
+    fs::remove_file(from)
+}
+
+/// Select a staging location for `to`, defaulting to the destination's own directory
+fn staging_path(temp_dir: Option<&Path>, to: &Path) -> PathBuf {
+    let dir = temp_dir
+        .or_else(|| to.parent())
+        .unwrap_or_else(|| Path::new(""));
+    let name = to.file_name().unwrap_or_default().to_string_lossy();
+    dir.join(format!(".{}.{}.mmv-tmp", name, std::process::id()))
+}
+
+fn check_writable(dir: &Path) -> io::Result<()> {
+    let probe = dir.join(format!(".mmv-probe.{}", std::process::id()));
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)?;
+    fs::remove_file(probe)
+}
+
+fn preview<P: AsRef<Path>>(
+    writer: &mut impl Write,
+    opts: &Args,
+    plan: Plan<'_, P>,
+) -> io::Result<Summary> {
+    let mut summary = Summary::default();
+    let color = opts.color_for(&io::stdout());
+
+    // Operations are listed in input order, whatever order they will be applied in
+    let mut operations: Vec<_> = plan.operations.collect();
+    if plan.strategy == Strategy::Reverse {
+        operations.reverse();
+    }
+
+    // Every source is measured up front so that the table's columns line up
+    let column = if opts.table {
+        operations
+            .iter()
+            .filter(|op| op.from != op.to)
+            .map(|op| op.from.display().to_string().chars().count())
+            .max()
+            .unwrap_or(0)
+    } else {
+        0
+    };
+
+    // With --head-tail, operations between the first and last few are counted but not shown
+    let total = operations.iter().filter(|op| op.from != op.to).count();
+    let elided = match opts.head_tail {
+        Some(n) if total > n * 2 => n..total - n,
+        _ => 0..0,
+    };
+
+    for op in &operations {
+        let shown = opts.show_operations() && !elided.contains(&summary.count);
+
+        if op.from == op.to {
+            if opts.show_unchanged && shown {
+                writeln!(writer, "= {}", op.from.display())?;
+            }
+            summary.unchanged += 1;
+            continue;
+        }
+
+        if shown {
+            if opts.show_sort_key {
+                if let Some(time) = sort_time(opts.sort, op.from)? {
+                    write!(writer, "[{}] ", format_sort_key(time))?;
+                }
+            }
+            if opts.table {
+                format_row(writer, op, column, color)?;
+            } else {
+                format_op(writer, op, opts.width, color)?;
+            }
+        } else if opts.show_operations() && summary.count == elided.start {
+            writeln!(writer, "... ({} more) ...", elided.len())?;
+        }
+        summary.count += 1;
+        flush_periodically(writer, summary.count)?;
+    }
+
+    for (from, to) in &plan.skipped {
+        if opts.show_operations() {
+            writeln!(
+                writer,
+                "{} -> {} (skipped, conflict)",
+                from.display(),
+                to.display()
+            )?;
+        }
+        summary.skipped += 1;
+    }
+
+    if opts.by_dir && opts.show_summary() {
+        write_destinations(writer, &operations)?;
+    }
+
+    if opts.show_summary() {
+        write!(writer, "Would rename {} files", summary.count)?;
+        summary.write_skipped(writer)?;
+        summary.write_unchanged(writer)?;
+        writeln!(writer, " ({} order)", plan.strategy)?;
+
+        if opts.explain {
+            explain(writer, plan.strategy, plan.reason.as_ref())?;
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Summarize how many files land in each destination directory
+fn write_destinations(writer: &mut impl Write, operations: &[Operation<'_>]) -> io::Result<()> {
+    use std::collections::BTreeMap;
+
+    let mut counts = BTreeMap::new();
+    for op in operations {
+        let dir = op.to.parent().unwrap_or(Path::new(""));
+        *counts.entry(dir).or_insert(0) += 1;
+    }
+
+    writeln!(writer, "Destinations:")?;
+    for (dir, count) in counts {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        let files = if count == 1 { "file" } else { "files" };
+        writeln!(writer, "  {}: {} {}", dir.display(), count, files)?;
+    }
+    Ok(())
+}
+
+/// Describe why the given iteration strategy was selected
+fn explain(
+    writer: &mut impl Write,
+    strategy: Strategy,
+    reason: Option<&Conflict>,
+) -> io::Result<()> {
+    if strategy == Strategy::Stage {
+        return writeln!(
+            writer,
+            "{} order selected: every file is moved to a temporary name before any is moved to its target",
+            strategy
+        );
+    }
+
+    match reason {
+        Some(conflict) => writeln!(
+            writer,
+            "{} order selected: forward order would move\n  {}\nonto\n  {}\nbefore that file had been moved out of the way",
+            strategy,
+            conflict.from.display(),
+            conflict.to.display()
+        ),
+        None => writeln!(
+            writer,
+            "{} order selected: no operation targets a file which has yet to be moved",
+            strategy
+        ),
+    }
+}
+
+/// Write a single operation, wrapping it onto two lines if it would exceed `width`
+///
+/// A width of zero disables wrapping.
+fn format_op(
+    writer: &mut impl Write,
+    op: &Operation<'_>,
+    width: usize,
+    color: bool,
+) -> io::Result<()> {
+    let formatted = format!("{} -> {}", op.from.display(), op.to.display());
+    let from = paint(op.from.display(), RED, color);
+    let to = paint(op.to.display(), GREEN, color);
+    if width > 0 && formatted.chars().count() > width {
+        writeln!(writer, "{}\n -> {}", from, to)
+    } else {
+        writeln!(writer, "{} -> {}", from, to)
+    }
+}
+
+/// Write a single operation as a table row, right-aligning the source in `column` characters
+fn format_row(
+    writer: &mut impl Write,
+    op: &Operation<'_>,
+    column: usize,
+    color: bool,
+) -> io::Result<()> {
+    let from = format!("{:>column$}", op.from.display().to_string());
+    writeln!(
+        writer,
+        "{} -> {}",
+        paint(from, RED, color),
+        paint(op.to.display(), GREEN, color)
+    )
+}
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const RESET: &str = "\x1b[0m";
+
+/// Wrap text in an ANSI color code, if coloring is enabled
+fn paint(text: impl std::fmt::Display, code: &str, color: bool) -> String {
+    if color {
+        format!("{}{}{}", code, text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+/// The number of operations between flushes of the output
+const FLUSH_INTERVAL: usize = 100;
+
+/// Flush the output after every `FLUSH_INTERVAL` operations
+fn flush_periodically(writer: &mut impl Write, count: usize) -> io::Result<()> {
+    if count.is_multiple_of(FLUSH_INTERVAL) {
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Format the elapsed time and rate of a batch, e.g. "Elapsed 2.000s (50.0 files/s)"
+fn format_throughput(count: usize, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64();
+    let rate = if count == 0 { 0.0 } else { count as f64 / secs };
+    format!("Elapsed {:.3}s ({:.1} files/s)", secs, rate)
+}
+
+/// Format a byte count using binary units, e.g. 1536 -> "1.5 KiB"
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn sort_paths(sort: SortMode, paths: impl Iterator<Item = PathBuf>) -> io::Result<Vec<PathBuf>> {
+    match sort {
+        SortMode::Created | SortMode::Modified => {
+            let mut with_time = paths
+                .map(|path| Ok((sort_time(sort, &path)?, path)))
+                .collect::<io::Result<Vec<_>>>()?;
+
+            // Files sharing a timestamp are ordered by path, so that numbering is reproducible
+            with_time.sort_unstable();
+            Ok(with_time.into_iter().map(|x| x.1).collect())
+        }
+
+        SortMode::Path => {
+            let mut paths: Vec<_> = paths.collect();
+            paths.sort_unstable();
+            Ok(paths)
+        }
+
+        SortMode::Tag => {
+            let mut with_tags = paths
+                .map(|path| Ok((tags::read(&path)?, path)))
+                .collect::<io::Result<Vec<_>>>()?;
+
+            // Untagged files follow the tagged ones, in path order
+            with_tags.sort_unstable_by(|(a, a_path), (b, b_path)| {
+                (a.is_empty(), a, a_path).cmp(&(b.is_empty(), b, b_path))
+            });
+            Ok(with_tags.into_iter().map(|x| x.1).collect())
+        }
+
+        SortMode::AsGiven => {
+            let mut seen = std::collections::HashSet::new();
+            Ok(paths.filter(|path| seen.insert(path.clone())).collect())
+        }
+    }
+}
+
+/// The time by which a date sort orders a file, or `None` for sorts which do not use one
+fn sort_time(sort: SortMode, path: &Path) -> io::Result<Option<SystemTime>> {
+    let time = match sort {
+        SortMode::Created => path.metadata().and_then(|meta| meta.created()),
+        SortMode::Modified => path.metadata().and_then(|meta| meta.modified()),
+        SortMode::Path | SortMode::Tag | SortMode::AsGiven => return Ok(None),
+    };
+
+    time.map(Some).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("unable to read metadata: {}\n  {}", e, path.display()),
+        )
+    })
+}
+
+/// Format a sort time for --show-sort-key, to the millisecond
+fn format_sort_key(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Local>::from(time)
+        .format("%Y-%m-%d %H:%M:%S%.3f")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use crate::{
+        args::{Args, ColorMode, ExecutionMode, SortMode, StrategyMode},
+        iter::{Forward, Operation, Strategy},
+        rename::Renamer,
+    };
+
+    #[test]
+    fn sort_errors_name_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let present = dir.path().join("present.txt");
+        let missing = dir.path().join("missing.txt");
+        fs::write(&present, b"").unwrap();
+
+        let paths = vec![present, missing.clone()];
+        let error = super::sort_paths(SortMode::Modified, paths.into_iter()).unwrap_err();
+        assert_eq!(std::io::ErrorKind::NotFound, error.kind());
+        assert!(error.to_string().contains(&*missing.to_string_lossy()));
+    }
+
+    #[test]
+    fn errors_as_json() {
+        let from = &["00", "01", "02"];
+        let to = &["01", "02", "03"];
+        let opts = Args {
+            strategy: crate::args::StrategyMode::Forward,
+            ..Default::default()
+        };
+
+        let error = super::select_iteration_mode(from, to, &opts).err().unwrap();
+        assert_eq!(
+            r#"{"cause":"unmoved","error":"conflict","from":"00","step":1,"to":"01"}"#,
+            super::error_json(&error).to_string()
+        );
+
+        let error = anyhow::anyhow!("something else");
+        assert_eq!(
+            r#"{"error":"other","message":"something else"}"#,
+            super::error_json(&error).to_string()
+        );
+    }
+
+    #[test]
+    fn format_bytes() {
+        assert_eq!("0 B", super::format_bytes(0));
+        assert_eq!("1023 B", super::format_bytes(1023));
+        assert_eq!("1.5 KiB", super::format_bytes(1536));
+        assert_eq!("2.0 GiB", super::format_bytes(2 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn format_throughput() {
+        use std::time::Duration;
+
+        assert_eq!(
+            "Elapsed 2.000s (50.0 files/s)",
+            super::format_throughput(100, Duration::from_secs(2))
+        );
+        assert_eq!(
+            "Elapsed 0.250s (12.0 files/s)",
+            super::format_throughput(3, Duration::from_millis(250))
+        );
+        assert_eq!(
+            "Elapsed 0.000s (0.0 files/s)",
+            super::format_throughput(0, Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn format_op_wraps_long_lines() {
+        let op = Operation {
+            from: Path::new("some/long/path/a.txt"),
+            to: Path::new("some/long/path/1.txt"),
+        };
+
+        let mut output = Vec::new();
+        super::format_op(&mut output, &op, 20, false).unwrap();
+        assert_eq!(
+            "some/long/path/a.txt\n -> some/long/path/1.txt\n",
+            String::from_utf8(output).unwrap()
+        );
+
+        let mut output = Vec::new();
+        super::format_op(&mut output, &op, 0, false).unwrap();
+        assert_eq!(
+            "some/long/path/a.txt -> some/long/path/1.txt\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn color_only_when_enabled() {
+        let op = Operation {
+            from: Path::new("a.txt"),
+            to: Path::new("1.txt"),
+        };
+
+        let mut output = Vec::new();
+        super::format_op(&mut output, &op, 0, true).unwrap();
+        assert_eq!(
+            "\x1b[31ma.txt\x1b[0m -> \x1b[32m1.txt\x1b[0m\n",
+            String::from_utf8(output).unwrap()
+        );
+
+        // --color never
+        let opts = Args {
+            color: ColorMode::Never,
+            table: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let plan = super::select_iteration_mode(&["a.txt"], &["1.txt"], &opts).unwrap();
+        super::preview(&mut output, &opts, plan).unwrap();
+        assert!(!String::from_utf8(output).unwrap().contains('\x1b'));
+
+        // The confirmation prompt follows --color too
+        let opts = Args {
+            color: ColorMode::Always,
+            ..Default::default()
+        };
+        let mut prompt = Vec::new();
+        let (from, to) = (["a.txt".into()], ["1.txt".into()]);
+        super::confirm(&mut &b"n\n"[..], &mut prompt, &opts, &from, &to).unwrap();
+        assert!(String::from_utf8(prompt).unwrap().contains("\x1b[31ma.txt"));
+    }
+
+    #[test]
+    fn out_dir_surfaces_collisions() {
+        let from = vec![
+            Path::new("a").join("x.txt"),
+            Path::new("a").join("y.txt"),
+            Path::new("b").join("x.txt"),
+        ];
+
+        let mut opts = Args {
+            template: "{o}".into(),
+            start: 1,
+            out_dir: Some("flat".into()),
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut opts, Some(from.len())).unwrap();
+        let to: Vec<_> = from.iter().map(|x| renamer.rename(x).unwrap()).collect();
+
+        assert_eq!(Path::new("flat").join("x.txt"), to[0]);
+        assert_eq!(Path::new("flat").join("y.txt"), to[1]);
+        assert!(super::select_iteration_mode(&from, &to, &Args::default()).is_err());
+    }
+
+    #[test]
+    fn parents_preserves_subtree() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("a/b")).unwrap();
+        fs::write(src.join("top.txt"), b"top").unwrap();
+        fs::write(src.join("a/mid.txt"), b"mid").unwrap();
+        fs::write(src.join("a/b/deep.txt"), b"deep").unwrap();
+
+        let out = dir.path().join("out");
+        let mut opts = Args {
+            template: "{o}".into(),
+            paths: vec![src.to_string_lossy().into_owned()],
+            start: 1,
+            out_dir: Some(out.clone()),
+            parents: true,
+            ..Default::default()
+        };
+
+        let (from, to) = super::render_paths(&mut opts).unwrap();
+        super::create_target_dirs(&opts, &to).unwrap();
+        super::do_rename(&mut Vec::new(), &opts, Forward::new(&from, &to)).unwrap();
+
+        assert_eq!(b"top", &*fs::read(out.join("top.txt")).unwrap());
+        assert_eq!(b"mid", &*fs::read(out.join("a/mid.txt")).unwrap());
+        assert_eq!(b"deep", &*fs::read(out.join("a/b/deep.txt")).unwrap());
+    }
+
+    #[test]
+    fn organize_by_date_moves_files_into_dated_folders() {
+        use chrono::{Local, TimeZone};
+
+        let dir = tempfile::tempdir().unwrap();
+        let files = [("a.jpg", 2023, 6), ("b.jpg", 2024, 1)];
+        for (name, year, month) in files {
+            let time = Local.with_ymd_and_hms(year, month, 15, 12, 0, 0).unwrap();
+            fs::File::create(dir.path().join(name))
+                .unwrap()
+                .set_modified(time.into())
+                .unwrap();
+        }
+
+        let path = dir.path().to_string_lossy();
+        let mut opts = Args::parse_from(["mmv", "--organize-by-date", "%Y-%m", "-fy", &path]);
+        assert!(opts.mkdirs);
+
+        let (from, to) = super::render_paths(&mut opts).unwrap();
+        super::create_target_dirs(&opts, &to).unwrap();
+        super::do_rename(&mut Vec::new(), &opts, Forward::new(&from, &to)).unwrap();
+        assert!(dir.path().join("2023-06/a.jpg").is_file());
+        assert!(dir.path().join("2024-01/b.jpg").is_file());
+    }
+
+    #[test]
+    fn emit_script_creates_dirs_with_mkdirs() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("2023-beach.jpg"), b"").unwrap();
+
+        let mut opts = Args {
+            template: "{g:1}/{o}".into(),
+            paths: vec![dir.path().to_string_lossy().into_owned()],
+            pattern: regex::Regex::new(r"(\d{4})-(.+)").ok(),
+            mkdirs: true,
+            ..Default::default()
+        };
+        let (from, to) = super::render_paths(&mut opts).unwrap();
+        let plan = super::select_iteration_mode(&from, &to, &opts).unwrap();
+
+        let mut output = Vec::new();
+        super::emit_script(&mut output, &opts, plan.operations).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let mkdir = format!("mkdir -p -- '{}'\n", dir.path().join("2023").display());
+        assert!(output.contains(&mkdir));
+        assert!(output.find(&mkdir) < output.find("mv -- "));
+    }
+
+    #[test]
+    fn insert_into_sequence() {
+        let dir = tempfile::tempdir().unwrap();
+        for n in 1..=5 {
+            fs::write(dir.path().join(format!("{}.jpg", n)), n.to_string()).unwrap();
+        }
+        fs::write(dir.path().join("new.jpg"), "new").unwrap();
+
+        let mut opts = Args {
+            template: "{n}".into(),
+            paths: vec![dir.path().join("*.jpg").to_string_lossy().into_owned()],
+            start: 1,
+            insert_at: Some(3),
+            ..Default::default()
+        };
+
+        let (from, to) = super::render_paths(&mut opts).unwrap();
+        let plan = super::select_iteration_mode(&from, &to, &Args::default()).unwrap();
+        assert_eq!(Strategy::Reverse, plan.strategy);
+        super::do_rename(&mut Vec::new(), &opts, plan.operations).unwrap();
+
+        let read = |n: u32| fs::read_to_string(dir.path().join(format!("{}.jpg", n))).unwrap();
+        let actual: Vec<_> = (1..=6).map(read).collect();
+        assert_eq!(actual, ["1", "2", "new", "3", "4", "5"]);
+        assert!(!dir.path().join("new.jpg").exists());
+    }
+
+    #[test]
+    fn collect_paths_counts_glob_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a.jpg", "b.jpg", "c.png", "d.jpg"] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+
+        let opts = Args {
+            paths: vec![dir.path().join("*.jpg").to_string_lossy().into_owned()],
+            count_only: true,
+            ..Default::default()
+        };
+        assert_eq!(3, super::collect_paths(&opts).unwrap().len());
+    }
+
+    #[test]
+    fn strict_rejects_unmatched_glob() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.jpg"), b"").unwrap();
+
+        let unmatched = dir.path().join("*.jgp").to_string_lossy().into_owned();
+        let mut opts = Args {
+            paths: vec![
+                dir.path().join("*.jpg").to_string_lossy().into_owned(),
+                unmatched.clone(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(1, super::collect_paths(&opts).unwrap().len());
+
+        opts.strict = true;
+        let error = super::collect_paths(&opts).unwrap_err();
+        assert_eq!(format!("no files matched: {unmatched}"), error.to_string());
+    }
+
+    #[test]
+    fn collect_paths_from_null_separated_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = ["a.txt", "two\nlines.txt"];
+        for name in names {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+
+        let list = dir.path().join("list");
+        let content: String = names
+            .iter()
+            .map(|name| format!("{}\0", dir.path().join(name).display()))
+            .collect();
+        fs::write(&list, content).unwrap();
+
+        let opts = Args {
+            from_file0: Some(list),
+            ..Default::default()
+        };
+        let expected: Vec<_> = names.iter().map(|name| dir.path().join(name)).collect();
+        assert_eq!(expected, super::collect_paths(&opts).unwrap());
+    }
+
+    #[test]
+    fn template_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let template = dir.path().join("template.txt");
+        fs::write(&template, "Fuzzy Bear {n:3} {o} \"$1\" & more\n").unwrap();
+        fs::write(dir.path().join("cub.jpg"), b"").unwrap();
+
+        let mut opts = Args {
+            template_file: Some(template),
+            paths: vec![dir.path().join("cub.jpg").to_string_lossy().into_owned()],
+            start: 1,
+            ..Default::default()
+        };
+
+        let (_, to) = super::render_paths(&mut opts).unwrap();
+        assert_eq!(
+            dir.path().join("Fuzzy Bear 001 cub \"$1\" & more.jpg"),
+            to[0]
+        );
+    }
+
+    #[test]
+    fn map_drives_renames() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("a.txt"), b"alpha").unwrap();
+        fs::write(path("b.txt"), b"bravo").unwrap();
+
+        let map = path("map.csv");
+        fs::write(
+            &map,
+            format!(
+                "{},{}\n{},\"{}\"\n{},{}\n",
+                path("a.txt").display(),
+                path("b.txt").display(),
+                path("b.txt").display(),
+                path("bravo, final.txt").display(),
+                path("missing.txt").display(),
+                path("nowhere.txt").display(),
+            ),
+        )
+        .unwrap();
+
+        let (from, to) = super::read_map(&map).unwrap();
+        assert_eq!(2, from.len());
+
+        let plan = super::select_iteration_mode(&from, &to, &Args::default()).unwrap();
+        assert_eq!(Strategy::Reverse, plan.strategy);
+
+        let mut output = Vec::new();
+        super::do_rename(&mut output, &Args::default(), plan.operations).unwrap();
+        assert_eq!(b"alpha", &*fs::read(path("b.txt")).unwrap());
+        assert_eq!(b"bravo", &*fs::read(path("bravo, final.txt")).unwrap());
+        assert!(!path("a.txt").exists());
+    }
+
+    #[test]
+    fn preview_reports_reverse_strategy() {
+        let from = &["00", "01", "02"];
+        let to = &["01", "02", "03"];
+        let plan = super::select_iteration_mode(from, to, &Args::default()).unwrap();
+        assert_eq!(Strategy::Reverse, plan.strategy);
+
+        let opts = Args {
+            explain: true,
+            ..Default::default()
+        };
+
+        let mut output = Vec::new();
+        super::preview(&mut output, &opts, plan).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Would rename 3 files (reverse order)\n"));
+        assert!(
+            output.contains("reverse order selected: forward order would move\n  00\nonto\n  01\n")
+        );
+    }
+
+    #[test]
+    fn preview_lists_operations_in_input_order() {
+        let from = &["00", "01", "02"];
+        let to = &["01", "02", "03"];
+        let plan = super::select_iteration_mode(from, to, &Args::default()).unwrap();
+        assert_eq!(Strategy::Reverse, plan.strategy);
+
+        let mut output = Vec::new();
+        super::preview(&mut output, &Args::default(), plan).unwrap();
+        assert_eq!(
+            "00 -> 01\n01 -> 02\n02 -> 03\nWould rename 3 files (reverse order)\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn preview_groups_by_destination() {
+        let from = &["a/1.txt", "a/2.txt", "b/3.txt", "b/4.txt"];
+        let to = &["out/x/1.txt", "out/y/2.txt", "out/x/3.txt", "out/x/4.txt"];
+        let plan = super::select_iteration_mode(from, to, &Args::default()).unwrap();
+
+        let opts = Args {
+            by_dir: true,
+            quiet: 1,
+            ..Default::default()
+        };
+
+        let mut output = Vec::new();
+        super::preview(&mut output, &opts, plan).unwrap();
+        assert_eq!(
+            "Destinations:\n  out/x: 3 files\n  out/y: 1 file\nWould rename 4 files (forward order)\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn preview_aligns_table() {
+        let from = &["a.txt", "longer.txt"];
+        let to = &["1.txt", "2.txt"];
+        let opts = Args {
+            table: true,
+            ..Default::default()
+        };
+
+        let mut output = Vec::new();
+        let plan = super::select_iteration_mode(from, to, &opts).unwrap();
+        super::preview(&mut output, &opts, plan).unwrap();
+        assert_eq!(
+            "     a.txt -> 1.txt\nlonger.txt -> 2.txt\nWould rename 2 files (forward order)\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn preview_shows_head_and_tail() {
+        let from: Vec<_> = (1..=10).map(|idx| format!("{idx}.txt")).collect();
+        let to: Vec<_> = (1..=10).map(|idx| format!("{idx}.md")).collect();
+        let opts = Args {
+            head_tail: Some(2),
+            ..Default::default()
+        };
+
+        let mut output = Vec::new();
+        let plan = super::select_iteration_mode(&from, &to, &opts).unwrap();
+        super::preview(&mut output, &opts, plan).unwrap();
+        assert_eq!(
+            "1.txt -> 1.md\n2.txt -> 2.md\n... (6 more) ...\n9.txt -> 9.md\n10.txt -> 10.md\nWould rename 10 files (forward order)\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn preview_marks_unchanged_files() {
+        let from = &["1.txt", "b.txt", "3.txt"];
+        let to = &["1.txt", "2.txt", "3.txt"];
+
+        let mut output = Vec::new();
+        let plan = super::select_iteration_mode(from, to, &Args::default()).unwrap();
+        super::preview(&mut output, &Args::default(), plan).unwrap();
+        assert_eq!(
+            "b.txt -> 2.txt\nWould rename 1 files, 2 unchanged (forward order)\n",
+            String::from_utf8(output).unwrap()
+        );
+
+        let opts = Args {
+            show_unchanged: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let plan = super::select_iteration_mode(from, to, &Args::default()).unwrap();
+        super::preview(&mut output, &opts, plan).unwrap();
+        assert_eq!(
+            "= 1.txt\nb.txt -> 2.txt\n= 3.txt\nWould rename 1 files, 2 unchanged (forward order)\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn forced_strategy() {
+        use crate::args::StrategyMode;
+
+        let from = &["00", "01", "02"];
+        let to = &["01", "02", "03"];
+
+        let opts = Args {
+            strategy: StrategyMode::Reverse,
+            ..Default::default()
+        };
+        let plan = super::select_iteration_mode(from, to, &opts).unwrap();
+        assert_eq!(Strategy::Reverse, plan.strategy);
+        assert!(plan.reason.is_none());
+
+        let opts = Args {
+            strategy: StrategyMode::Forward,
+            ..Default::default()
+        };
+        assert!(super::select_iteration_mode(from, to, &opts).is_err());
+
+        let from = &["01", "02", "03"];
+        let to = &["00", "01", "02"];
+        let opts = Args {
+            strategy: StrategyMode::Reverse,
+            ..Default::default()
+        };
+        assert!(super::select_iteration_mode(from, to, &opts).is_err());
+    }
+
+    #[test]
+    fn preview_flushes_incrementally() {
+        use std::io::{self, Write};
+
+        /// Records how much had been written at each flush
+        #[derive(Default)]
+        struct FlushRecorder {
+            written: usize,
+            flushes: Vec<usize>,
+        }
+
+        impl Write for FlushRecorder {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.written += buf.len();
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.flushes.push(self.written);
+                Ok(())
+            }
+        }
+
+        let from: Vec<_> = (0..250).map(|i| format!("a{:03}", i)).collect();
+        let to: Vec<_> = (0..250).map(|i| format!("b{:03}", i)).collect();
+        let plan = super::select_iteration_mode(&from, &to, &Args::default()).unwrap();
+
+        let mut recorder = FlushRecorder::default();
+        super::preview(&mut recorder, &Args::default(), plan).unwrap();
+
+        // "aNNN -> bNNN\n" is 13 bytes
+        assert_eq!(recorder.flushes, [1300, 2600]);
+        assert!(recorder.written > 2600);
+    }
+
+    #[test]
+    fn preview_reports_forward_strategy() {
+        let from = &["01", "02", "03"];
+        let to = &["00", "01", "02"];
+        let plan = super::select_iteration_mode(from, to, &Args::default()).unwrap();
+        assert_eq!(Strategy::Forward, plan.strategy);
+
+        let mut output = Vec::new();
+        super::preview(&mut output, &Args::default(), plan).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.ends_with("Would rename 3 files (forward order)\n"));
+    }
+
+    #[test]
+    fn quiet_suppresses_output() {
+        let from = &["a.txt", "b.txt"];
+        let to = &["1.txt", "2.txt"];
+
+        let opts = Args {
+            quiet: 1,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let plan = super::select_iteration_mode(from, to, &Args::default()).unwrap();
+        super::preview(&mut output, &opts, plan).unwrap();
+        assert_eq!(
+            "Would rename 2 files (forward order)\n",
+            String::from_utf8(output).unwrap()
+        );
+
+        let opts = Args {
+            quiet: 2,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let plan = super::select_iteration_mode(from, to, &Args::default()).unwrap();
+        super::preview(&mut output, &opts, plan).unwrap();
+        assert!(output.is_empty());
+
+        let dir = tempfile::tempdir().unwrap();
+        let from = vec![dir.path().join("a.txt")];
+        let to = vec![dir.path().join("1.txt")];
+        fs::write(&from[0], b"hello").unwrap();
+
+        let mut output = Vec::new();
+        super::do_rename(&mut output, &opts, Forward::new(&from, &to)).unwrap();
+        assert!(output.is_empty());
+        assert!(to[0].exists());
+    }
+
+    #[test]
+    fn copy_reports_total_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let from: Vec<_> = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        let to: Vec<_> = ["1.txt", "2.txt", "3.txt"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+
+        fs::write(&from[0], b"hello").unwrap();
+        fs::write(&from[1], b"world!").unwrap();
+        fs::write(&from[2], [0u8; 1024]).unwrap();
+
+        let mut output = Vec::new();
+        let summary =
+            super::do_copy(&mut output, &Args::default(), Forward::new(&from, &to)).unwrap();
+        assert_eq!(3, summary.count);
+        assert_eq!(1035, summary.bytes);
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.ends_with("Copied 3 files (1.0 KiB)\n"));
+    }
+
+    #[test]
+    fn copy_skips_identical_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let from: Vec<_> = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        let to: Vec<_> = ["1.txt", "2.txt", "3.txt"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+
+        fs::write(&from[0], b"alpha").unwrap();
+        fs::write(&from[1], b"bravo").unwrap();
+        fs::write(&from[2], b"charlie").unwrap();
+        fs::write(&to[0], b"alpha").unwrap();
+        fs::write(&to[1], b"BRAVO").unwrap();
+
+        let opts = Args {
+            skip_identical: true,
+            ..Default::default()
+        };
+
+        let mut output = Vec::new();
+        let summary = super::do_copy(&mut output, &opts, Forward::new(&from, &to)).unwrap();
+        assert_eq!(2, summary.count);
+        assert_eq!(1, summary.skipped);
+        assert_eq!(b"bravo", &*fs::read(&to[1]).unwrap());
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.ends_with("Copied 2 files (12 B), skipped 1\n"));
+    }
+
+    #[test]
+    fn write_map_omits_skipped_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = vec![dir.path().join("a.txt"), dir.path().join("b.txt")];
+        let to = vec![dir.path().join("1.txt"), dir.path().join("2.txt")];
+        fs::write(&from[0], b"alpha").unwrap();
+        fs::write(&from[1], b"bravo").unwrap();
+        fs::write(&to[0], b"alpha").unwrap();
+
+        let opts = Args {
+            skip_identical: true,
+            write_map: Some(dir.path().join("map.csv")),
+            ..Default::default()
+        };
+        let summary = super::do_copy(&mut Vec::new(), &opts, Forward::new(&from, &to)).unwrap();
+        assert_eq!(1, summary.skipped);
+        assert_eq!(vec![(from[1].clone(), to[1].clone())], summary.completed);
+    }
+
+    #[test]
+    fn skipping_a_conflict_lets_the_rest_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut from: Vec<_> = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        let mut to: Vec<_> = ["x.txt", "y.txt", "x.txt"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        for path in &from {
+            fs::write(path, b"").unwrap();
+        }
+
+        let opts = Args::default();
+        let (mut declined_from, mut declined_to) = (from.clone(), to.clone());
+        let mut input = &b"n\n\n"[..];
+        let result =
+            super::resolve_conflicts(&mut declined_from, &mut declined_to, &opts, |conflict| {
+                super::ask_to_skip(&mut input, &mut Vec::new(), conflict)
+            });
+        assert!(result.is_err());
+
+        let mut input = &b"y\n"[..];
+        let mut prompts = Vec::new();
+        let skipped = super::resolve_conflicts(&mut from, &mut to, &opts, |conflict| {
+            super::ask_to_skip(&mut input, &mut prompts, conflict)
+        })
+        .unwrap();
+        assert_eq!(1, skipped.len());
+        assert!(String::from_utf8(prompts)
+            .unwrap()
+            .contains("Skip this operation? [y/N]"));
+        assert_eq!(2, from.len());
+        assert!(!from.contains(&dir.path().join("c.txt")));
+
+        let plan = super::select_iteration_mode(&from, &to, &opts).unwrap();
+        super::do_rename(&mut Vec::new(), &opts, plan.operations).unwrap();
+        assert!(dir.path().join("x.txt").exists());
+        assert!(dir.path().join("y.txt").exists());
+        assert!(dir.path().join("c.txt").exists());
+    }
+
+    #[test]
+    fn quiet_conflicts_preview_continues() {
+        let from: Vec<_> = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let to: Vec<_> = ["x.txt", "y.txt", "x.txt"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let opts = Args {
+            quiet_conflicts: true,
+            ..Default::default()
+        };
+        assert!(super::select_iteration_mode(&from, &to, &opts).is_err());
+
+        let (mut from, mut to) = (from, to);
+        let skipped = super::resolve_conflicts(&mut from, &mut to, &opts, |_| Ok(true)).unwrap();
+        let mut plan = super::select_iteration_mode(&from, &to, &opts).unwrap();
+        plan.skipped = skipped;
+
+        let mut output = Vec::new();
+        let summary = super::preview(&mut output, &opts, plan).unwrap();
+        assert_eq!(2, summary.count);
+        assert_eq!(
+            "a.txt -> x.txt\nb.txt -> y.txt\nc.txt -> x.txt (skipped, conflict)\n\
+            Would rename 2 files, skipped 1 (forward order)\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn staged_rename_handles_permutations() {
+        let dir = tempfile::tempdir().unwrap();
+        let from: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        let to: Vec<_> = ["c", "b", "a"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        for path in &from {
+            fs::write(path, path.file_name().unwrap().as_encoded_bytes()).unwrap();
+        }
+
+        assert!(super::select_iteration_mode(&from, &to, &Args::default()).is_err());
+
+        let opts = Args {
+            strategy: StrategyMode::Stage,
+            ..Default::default()
+        };
+        let plan = super::select_iteration_mode(&from, &to, &opts).unwrap();
+        assert_eq!(Strategy::Stage, plan.strategy);
+
+        let mut output = Vec::new();
+        let summary = super::do_staged_rename(&mut output, &opts, plan.operations).unwrap();
+        assert_eq!(2, summary.count);
+        assert_eq!(1, summary.unchanged);
+        assert_eq!("c", fs::read_to_string(dir.path().join("a")).unwrap());
+        assert_eq!("b", fs::read_to_string(dir.path().join("b")).unwrap());
+        assert_eq!("a", fs::read_to_string(dir.path().join("c")).unwrap());
+        assert_eq!(3, fs::read_dir(dir.path()).unwrap().count());
+
+        let duplicate = vec![dir.path().join("a"), dir.path().join("a")];
+        assert!(super::select_iteration_mode(&from[..2], &duplicate, &opts).is_err());
+    }
+
+    #[test]
+    fn staged_rename_returns_files_blocked_by_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        let missing = dir.path().join("missing").join("c");
+        fs::write(&a, b"a").unwrap();
+        fs::write(&b, b"b").unwrap();
+
+        let opts = Args {
+            strategy: StrategyMode::Stage,
+            continue_on_error: true,
+            ..Default::default()
+        };
+
+        // b cannot be staged beside a directory that does not exist, so a must not take its place
+        let ops = [(&a, &b), (&b, &missing)]
+            .into_iter()
+            .map(|(from, to)| Operation { from, to });
+        let summary = super::do_staged_rename(&mut Vec::new(), &opts, ops).unwrap();
+        assert_eq!(0, summary.count);
+        assert_eq!(2, summary.failures.len());
+        assert_eq!(b"a", &*fs::read(&a).unwrap());
+        assert_eq!(b"b", &*fs::read(&b).unwrap());
+        assert_eq!(2, fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn out_dir_inside_input_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), b"").unwrap();
+
+        let mut opts = Args {
+            template: "{o}".into(),
+            paths: vec![dir.path().to_string_lossy().into_owned()],
+            out_dir: Some(dir.path().join("sorted")),
+            ..Default::default()
+        };
+        let (from, to) = super::render_paths(&mut opts).unwrap();
+        let error = super::check_nesting(&opts, &from, &to).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("output directory is inside an input directory"));
+
+        opts.out_dir = Some(dir.path().to_owned());
+        assert!(super::check_nesting(&opts, &from, &to).is_ok());
+
+        let from = [dir.path().join("photos")];
+        let to = [dir.path().join("photos/photos")];
+        assert!(super::check_nesting(&Args::default(), &from, &to).is_err());
+    }
+
+    #[test]
+    fn mkdirs_creates_target_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let to = vec![
+            dir.path().join("2021/foo.jpg"),
+            dir.path().join("2022/bar.jpg"),
+        ];
+
+        let error = super::create_target_dirs(&Args::default(), &to).unwrap_err();
+        assert!(error.to_string().contains("--mkdirs"));
+
+        let opts = Args {
+            mkdirs: true,
+            ..Default::default()
+        };
+        super::create_target_dirs(&opts, &to).unwrap();
+        assert!(dir.path().join("2021").is_dir());
+        assert!(dir.path().join("2022").is_dir());
+    }
+
+    #[test]
+    fn confirmation_warns_about_large_batches() {
+        let from: Vec<_> = ["a", "b", "c"].iter().map(PathBuf::from).collect();
+        let to: Vec<_> = ["1", "2", "3"].iter().map(PathBuf::from).collect();
+
+        let prompt = |confirm_threshold, answer: &[u8]| {
+            let opts = Args {
+                confirm_threshold,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            let confirmed =
+                super::confirm(&mut &answer[..], &mut output, &opts, &from, &to).unwrap();
+            (confirmed, String::from_utf8(output).unwrap())
+        };
+
+        let (confirmed, output) = prompt(2, b"y\n");
+        assert!(confirmed);
+        assert!(output.contains("WARNING: 3 files will be moved"));
+        assert!(output.ends_with("Move 3 files? [y/N] "));
+
+        let (confirmed, output) = prompt(3, b"\n");
+        assert!(!confirmed);
+        assert!(!output.contains("WARNING"));
+        assert!(output.starts_with("a -> 1\n"));
+    }
+
+    #[test]
+    fn free_space_shortfall() {
+        assert_eq!(None, super::space_shortfall(100, 1000, 0));
+        assert_eq!(None, super::space_shortfall(100, 1000, 900));
+        assert_eq!(Some(1), super::space_shortfall(100, 1000, 901));
+        assert_eq!(Some(500), super::space_shortfall(1500, 1000, 0));
+        assert_eq!(Some(u64::MAX - 10), super::space_shortfall(u64::MAX, 10, 1));
+    }
+
+    #[test]
+    fn copy_needs_free_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = vec![dir.path().join("a.txt")];
+        let to = vec![dir.path().join("b.txt")];
+        fs::write(&from[0], b"data").unwrap();
+
+        let mut opts = Args::default();
+        assert!(super::check_free_space(&opts, &from, &to).is_ok());
+
+        // Checked before target directories are created
+        let nested = vec![dir.path().join("new/b.txt")];
+        assert!(super::check_free_space(&opts, &from, &nested).is_ok());
+
+        opts.min_free_space = u64::MAX;
+        let error = super::check_free_space(&opts, &from, &to).unwrap_err();
+        assert!(error.to_string().starts_with("not enough free space"));
+    }
+
+    #[test]
+    fn order_by_aligns_to_reference() {
+        let dir = tempfile::tempdir().unwrap();
+        let (photos, raw) = (dir.path().join("photos"), dir.path().join("raw"));
+        fs::create_dir(&photos).unwrap();
+        for name in ["beach.jpg", "cliff.jpg", "dune.jpg", "extra.jpg"] {
+            fs::write(photos.join(name), b"").unwrap();
+        }
+
+        // Sorted by path, the reference puts the dune first
+        for name in ["a/dune.raw", "b/beach.raw", "c/cliff.raw"] {
+            let path = raw.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+
+        let mut opts = Args {
+            template: "{n}-{o}".into(),
+            paths: vec![photos.to_string_lossy().into_owned()],
+            start: 1,
+            order_by: Some(raw),
+            ..Default::default()
+        };
+        let (_, to) = super::render_paths(&mut opts).unwrap();
+        let expected: Vec<_> = ["1-dune.jpg", "2-beach.jpg", "3-cliff.jpg", "4-extra.jpg"]
+            .iter()
+            .map(|name| photos.join(name))
+            .collect();
+        assert_eq!(expected, to);
+    }
+
+    #[test]
+    fn range_selects_a_slice() {
+        let dir = tempfile::tempdir().unwrap();
+        for idx in 0..10 {
+            fs::write(dir.path().join(format!("f{idx}.txt")), b"").unwrap();
+        }
+
+        let rename = |range_absolute| {
+            let mut opts = Args {
+                template: "x{n}".into(),
+                paths: vec![dir.path().to_string_lossy().into_owned()],
+                start: 1,
+                range: Some(3..6),
+                range_absolute,
+                ..Default::default()
+            };
+            super::render_paths(&mut opts).unwrap()
+        };
+
+        let (from, to) = rename(false);
+        let names = |paths: &[std::path::PathBuf]| -> Vec<String> {
+            paths
+                .iter()
+                .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+        assert_eq!(vec!["f3.txt", "f4.txt", "f5.txt"], names(&from));
+        assert_eq!(vec!["x1.txt", "x2.txt", "x3.txt"], names(&to));
+
+        let (_, to) = rename(true);
+        assert_eq!(vec!["x04.txt", "x05.txt", "x06.txt"], names(&to));
+
+        let (paths, offset) = super::select_range(from, 1..20);
+        assert_eq!(vec!["f4.txt", "f5.txt"], names(&paths));
+        assert_eq!(1, offset);
+    }
+
+    #[test]
+    fn as_given_keeps_input_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = ["c.txt", "a.txt", "b.txt"];
+        for name in names {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+
+        let arg = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        let mut opts = Args {
+            template: "{n}-{o}".into(),
+            paths: vec![arg("c.txt"), arg("a.txt"), arg("c.txt"), arg("b.txt")],
+            start: 1,
+            sort: SortMode::AsGiven,
+            ..Default::default()
+        };
+        let (from, to) = super::render_paths(&mut opts).unwrap();
+
+        let expected: Vec<_> = names.iter().map(|name| dir.path().join(name)).collect();
+        assert_eq!(expected, from);
+        assert_eq!(dir.path().join("1-c.txt"), to[0]);
+        assert_eq!(dir.path().join("2-a.txt"), to[1]);
+        assert_eq!(dir.path().join("3-b.txt"), to[2]);
+    }
+
+    #[test]
+    fn preview_shows_sort_keys() {
+        use std::time::{Duration, SystemTime};
+
+        let dir = tempfile::tempdir().unwrap();
+        let from = vec![dir.path().join("a.txt"), dir.path().join("b.txt")];
+        let to = vec![dir.path().join("1.txt"), dir.path().join("2.txt")];
+        let times = [
+            SystemTime::UNIX_EPOCH + Duration::from_millis(1_600_000_000_250),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000),
+        ];
+        for (path, time) in from.iter().zip(times) {
+            fs::write(path, b"").unwrap();
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+        }
+
+        let opts = Args {
+            sort: SortMode::Modified,
+            show_sort_key: true,
+            ..Default::default()
+        };
+        let plan = super::select_iteration_mode(&from, &to, &opts).unwrap();
+        let mut output = Vec::new();
+        super::preview(&mut output, &opts, plan).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let first = output.lines().next().unwrap();
+        let key = chrono::DateTime::<chrono::Local>::from(times[0]).format("%Y-%m-%d %H:%M:%S%.3f");
+        assert_eq!(
+            format!("[{}] {} -> {}", key, from[0].display(), to[0].display()),
+            first
+        );
+        assert!(first.contains(".250]"));
+    }
+
+    #[test]
+    fn date_sort_ties_are_ordered_by_path() {
+        use std::time::SystemTime;
+
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<_> = ["c.txt", "a.txt", "b.txt"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        for path in &paths {
+            fs::write(path, b"").unwrap();
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+        }
+
+        let sorted = super::sort_paths(SortMode::Modified, paths.into_iter()).unwrap();
+        let names: Vec<_> = sorted
+            .iter()
+            .map(|path| path.file_name().unwrap())
+            .collect();
+        assert_eq!(vec!["a.txt", "b.txt", "c.txt"], names);
+    }
+
+    #[test]
+    fn update_skips_newer_targets() {
+        use std::time::{Duration, SystemTime};
+
+        let dir = tempfile::tempdir().unwrap();
+        let from = vec![dir.path().join("a.txt"), dir.path().join("b.txt")];
+        let to = vec![dir.path().join("1.txt"), dir.path().join("2.txt")];
+
+        let now = SystemTime::now();
+        let set_modified = |path: &Path, time: SystemTime| {
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+        };
+
+        for (path, content) in from.iter().chain(&to).zip(["alpha", "bravo", "old", "new"]) {
+            fs::write(path, content).unwrap();
+            set_modified(path, now);
+        }
+        set_modified(&to[0], now - Duration::from_secs(60));
+        set_modified(&to[1], now + Duration::from_secs(60));
+
+        let opts = Args {
+            update: true,
+            ..Default::default()
+        };
+
+        let mut output = Vec::new();
+        let summary = super::do_copy(&mut output, &opts, Forward::new(&from, &to)).unwrap();
+        assert_eq!(1, summary.count);
+        assert_eq!(1, summary.skipped);
+        assert_eq!(b"alpha", &*fs::read(&to[0]).unwrap());
+        assert_eq!(b"new", &*fs::read(&to[1]).unwrap());
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("(skipped, destination is not older)"));
+    }
+
+    #[test]
+    fn staging_uses_temp_dir() {
+        let temp = tempfile::tempdir().unwrap();
+        let staging = super::staging_path(Some(temp.path()), Path::new("out/1.txt"));
+        assert_eq!(Some(temp.path()), staging.parent());
+
+        let staging = super::staging_path(None, Path::new("out/1.txt"));
+        assert_eq!(Some(Path::new("out")), staging.parent());
+    }
+
+    #[test]
+    fn move_across_devices_stages_in_temp_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let temp = tempfile::tempdir().unwrap();
+        let from = dir.path().join("a.txt");
+        let to = dir.path().join("1.txt");
+        fs::write(&from, b"hello").unwrap();
+
+        super::move_across_devices(&from, &to, Some(temp.path())).unwrap();
+        assert!(!from.exists());
+        assert_eq!(b"hello", &*fs::read(&to).unwrap());
+        assert_eq!(0, fs::read_dir(temp.path()).unwrap().count());
+
+        assert!(super::check_writable(temp.path()).is_ok());
+        assert!(super::check_writable(&temp.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn continue_on_error_records_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let from: Vec<_> = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        let to: Vec<_> = ["1.txt", "2.txt", "3.txt"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+
+        // b.txt is missing, so its move will fail
+        fs::write(&from[0], b"alpha").unwrap();
+        fs::write(&from[2], b"charlie").unwrap();
+
+        let opts = Args {
+            continue_on_error: true,
+            ..Default::default()
+        };
+
+        let mut output = Vec::new();
+        let summary = super::do_rename(&mut output, &opts, Forward::new(&from, &to)).unwrap();
+        assert_eq!(2, summary.count);
+        assert_eq!(1, summary.failures.len());
+        assert_eq!(from[1], summary.failures[0].from);
+        assert!(to[0].exists() && to[2].exists());
+
+        let mut report = Vec::new();
+        summary.write_failures(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("Failed 1 operation:\n"));
+        assert!(report.contains(&format!("  {} -> {}: ", from[1].display(), to[1].display())));
+    }
+
+    #[test]
+    fn continue_on_error_does_not_overwrite_stranded_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<_> = ["01.txt", "02.txt", "03.txt"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+
+        // 03.txt is in the way of 02.txt as a directory, so 02.txt stays put and 01.txt must wait
+        fs::write(&paths[0], b"one").unwrap();
+        fs::write(&paths[1], b"two").unwrap();
+        fs::create_dir(&paths[2]).unwrap();
+        fs::write(paths[2].join("occupied"), b"").unwrap();
+
+        let opts = Args {
+            continue_on_error: true,
+            ..Default::default()
+        };
+
+        let ops = [(0, 1), (1, 2)]
+            .into_iter()
+            .rev()
+            .map(|(from, to)| Operation {
+                from: &paths[from],
+                to: &paths[to],
+            });
+        let summary = super::do_rename(&mut Vec::new(), &opts, ops).unwrap();
+        assert_eq!(0, summary.count);
+        assert_eq!(2, summary.failures.len());
+        assert_eq!(b"one", &*fs::read(&paths[0]).unwrap());
+        assert_eq!(b"two", &*fs::read(&paths[1]).unwrap());
+    }
+
+    #[test]
+    fn atomic_rename_rolls_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let from: Vec<_> = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        let to: Vec<_> = ["1.txt", "2.txt", "3.txt"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+
+        // c.txt is missing, so the batch fails after two moves
+        fs::write(&from[0], b"alpha").unwrap();
+        fs::write(&from[1], b"bravo").unwrap();
+
+        let opts = Args {
+            atomic: true,
+            ..Default::default()
+        };
+
+        let mut output = Vec::new();
+        let error = super::do_rename(&mut output, &opts, Forward::new(&from, &to)).unwrap_err();
+        assert!(error.to_string().ends_with("rolled back 2 operations"));
+        assert_eq!(b"alpha", &*fs::read(&from[0]).unwrap());
+        assert_eq!(b"bravo", &*fs::read(&from[1]).unwrap());
+        assert!(to.iter().all(|path| !path.exists()));
+    }
+
+    #[test]
+    fn atomic_rename_discards_kept_originals() {
+        let dir = tempfile::tempdir().unwrap();
+        let from: Vec<_> = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        for path in &from {
+            fs::write(path, b"data").unwrap();
+        }
+
+        // The last target's directory is missing, so its move fails after its original is kept
+        let to = vec![
+            dir.path().join("1.txt"),
+            dir.path().join("2.txt"),
+            dir.path().join("missing/3.txt"),
+        ];
+        let opts = Args {
+            atomic: true,
+            keep_original: true,
+            ..Default::default()
+        };
+
+        let error = super::do_rename(&mut Vec::new(), &opts, Forward::new(&from, &to)).unwrap_err();
+        assert!(error.to_string().ends_with("rolled back 2 operations"));
+        assert!(from.iter().all(|path| path.exists()));
+        let mut names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(vec!["a.txt", "b.txt", "c.txt"], names);
+    }
+
+    #[test]
+    fn check_backups_finds_clashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let backups = dir.path().join("backups");
+        fs::create_dir(&backups).unwrap();
+        let from = vec![dir.path().join("a/01.jpg"), dir.path().join("b/01.jpg")];
+        let to = vec![dir.path().join("1.jpg"), dir.path().join("2.jpg")];
+
+        let opts = Args {
+            keep_original: true,
+            backup_dir: Some(backups.clone()),
+            ..Default::default()
+        };
+        let error = super::check_backups(&opts, &from, &to).unwrap_err();
+        assert!(error.to_string().contains("would both be kept as"));
+        assert!(super::check_backups(&opts, &from[..1], &to[..1]).is_ok());
+
+        fs::write(backups.join("01.jpg"), b"").unwrap();
+        let error = super::check_backups(&opts, &from[..1], &to[..1]).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("would overwrite an existing file"));
+    }
+
+    #[test]
+    fn atomic_copy_rolls_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = vec![dir.path().join("a.txt"), dir.path().join("b.txt")];
+        let to = vec![dir.path().join("1.txt"), dir.path().join("2.txt")];
+        fs::write(&from[0], b"alpha").unwrap();
+
+        let opts = Args {
+            atomic: true,
+            ..Default::default()
+        };
+
+        let mut output = Vec::new();
+        assert!(super::do_copy(&mut output, &opts, Forward::new(&from, &to)).is_err());
+        assert!(from[0].exists());
+        assert!(to.iter().all(|path| !path.exists()));
+    }
+
+    #[test]
+    fn atomic_copy_refuses_to_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = vec![dir.path().join("a.txt")];
+        let to = vec![dir.path().join("1.txt")];
+        fs::write(&from[0], b"alpha").unwrap();
+
+        let mut opts = Args {
+            atomic: true,
+            skip_identical: true,
+            ..Default::default()
+        };
+        assert!(super::check_overwrites(&opts, &from, &to).is_ok());
+
+        // An identical target is left alone, so only a different one is at risk
+        fs::write(&to[0], b"alpha").unwrap();
+        assert!(super::check_overwrites(&opts, &from, &to).is_ok());
+        fs::write(&to[0], b"one").unwrap();
+        assert!(super::check_overwrites(&opts, &from, &to).is_err());
+
+        opts.atomic = false;
+        assert!(super::check_overwrites(&opts, &from, &to).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_permissions_finds_read_only_targets() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let locked = dir.path().join("locked");
+        fs::create_dir(&locked).unwrap();
+        let from = vec![dir.path().join("a.txt"), dir.path().join("b.txt")];
+        for path in &from {
+            fs::write(path, "").unwrap();
+        }
+        let to = vec![dir.path().join("1.txt"), locked.join("new/2.txt")];
+
+        let check = |mode, from: &[PathBuf]| {
+            let mut output = Vec::new();
+            let problems = super::check_permissions(&mut output, mode, from, &to).unwrap();
+            (problems, String::from_utf8(output).unwrap())
+        };
+        assert_eq!(0, check(ExecutionMode::Preview, &from).0);
+
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o555)).unwrap();
+        // Permissions do not bind root, so there is nothing to find
+        if super::check_writable(&locked).is_ok() {
+            return;
+        }
+
+        let (problems, output) = check(ExecutionMode::Preview, &from);
+        assert_eq!(1, problems);
+        assert!(output.starts_with(&format!(
+            "unwritable target directory: {}",
+            locked.display()
+        )));
+
+        // A file in a read-only directory can be copied but not moved
+        let held = vec![from[0].clone(), locked.join("held.txt")];
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(&held[1], "").unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o555)).unwrap();
+        let (problems, output) = check(ExecutionMode::Preview, &held);
+        assert_eq!(2, problems);
+        assert!(output.starts_with(&format!(
+            "unwritable source directory: {}",
+            locked.display()
+        )));
+        assert_eq!(1, check(ExecutionMode::Copy, &held).0);
+
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn verify_after_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        let from: Vec<_> = ["00", "01", "02"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        let to: Vec<_> = ["01", "02", "03"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        for path in &from {
+            fs::write(path, b"").unwrap();
+        }
+
+        let plan = super::select_iteration_mode(&from, &to, &Args::default()).unwrap();
+        let mut output = Vec::new();
+        super::do_rename(&mut output, &Args::default(), plan.operations).unwrap();
+
+        let mut output = Vec::new();
+        assert_eq!(
+            0,
+            super::verify(&mut output, ExecutionMode::Move, &from, &to).unwrap()
+        );
+        assert!(output.is_empty());
+
+        fs::remove_file(&to[2]).unwrap();
+        fs::write(&from[0], b"").unwrap();
+        assert_eq!(
+            2,
+            super::verify(&mut output, ExecutionMode::Move, &from, &to).unwrap()
+        );
+    }
+
+    #[test]
+    fn errors_abort_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = vec![dir.path().join("a.txt"), dir.path().join("b.txt")];
+        let to = vec![dir.path().join("1.txt"), dir.path().join("2.txt")];
+        fs::write(&from[1], b"bravo").unwrap();
+
+        let mut output = Vec::new();
+        let result = super::do_rename(&mut output, &Args::default(), Forward::new(&from, &to));
+        assert!(result.is_err());
+        assert!(from[1].exists());
+    }
+
+    #[test]
+    fn keep_original_copies_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = vec![dir.path().join("a.txt"), dir.path().join("b.txt")];
+        let to = vec![dir.path().join("1.txt"), dir.path().join("2.txt")];
+        for path in &from {
+            fs::write(path, b"data").unwrap();
+        }
+
+        let opts = Args {
+            keep_original: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let summary = super::do_rename(&mut output, &opts, Forward::new(&from, &to)).unwrap();
+        assert_eq!(2, summary.originals);
+        assert!(to.iter().all(|path| path.exists()));
+        assert!(!from[0].exists());
+        assert_eq!(b"data", &*fs::read(dir.path().join("a.txt.orig")).unwrap());
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .ends_with("Moved 2 files (8 B), kept 2 originals\n"));
+
+        let backups = dir.path().join("backups");
+        fs::create_dir(&backups).unwrap();
+        let opts = Args {
+            keep_original: true,
+            backup_dir: Some(backups.clone()),
+            ..Default::default()
+        };
+        super::do_rename(&mut Vec::new(), &opts, Forward::new(&to, &from)).unwrap();
+        assert!(from.iter().all(|path| path.exists()));
+        assert!(backups.join("1.txt").exists());
+        assert!(backups.join("2.txt").exists());
+    }
+
+    #[test]
+    fn log_records_each_operation() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = vec![dir.path().join("a.txt"), dir.path().join("b.txt")];
+        let to = vec![dir.path().join("1.txt"), dir.path().join("2.txt")];
+        fs::write(&from[0], b"data").unwrap();
+
+        let log = dir.path().join("mmv.log");
+        let opts = Args {
+            log: Some(log.clone()),
+            continue_on_error: true,
+            quiet: 2,
+            ..Default::default()
+        };
+        let summary = super::do_rename(&mut Vec::new(), &opts, Forward::new(&from, &to)).unwrap();
+        assert_eq!(1, summary.failures.len());
+
+        let content = fs::read_to_string(&log).unwrap();
+        let records: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(2, records.len());
+        assert_eq!("ok", records[0]["result"]);
+        assert_eq!(*to[0].to_string_lossy(), records[0]["to"]);
+        assert!(records[1]["result"]
+            .as_str()
+            .unwrap()
+            .starts_with("failed: "));
+    }
+
+    #[test]
+    fn rename_reports_total_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = vec![dir.path().join("a.txt"), dir.path().join("b.txt")];
+        let to = vec![dir.path().join("1.txt"), dir.path().join("2.txt")];
+
+        fs::write(&from[0], b"hello").unwrap();
+        fs::write(&from[1], b"world!").unwrap();
+
+        let mut output = Vec::new();
+        let summary =
+            super::do_rename(&mut output, &Args::default(), Forward::new(&from, &to)).unwrap();
+        assert_eq!(2, summary.count);
+        assert_eq!(11, summary.bytes);
+        assert!(to.iter().all(|path| path.exists()));
+    }
+
+    #[test]
+    fn unchanged_names_are_not_moved() {
+        let dir = tempfile::tempdir().unwrap();
+        let from: Vec<_> = ["1.txt", "2.txt", "c.txt", "d.txt"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        let to: Vec<_> = ["1.txt", "2.txt", "3.txt", "4.txt"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+
+        for path in &from {
+            fs::write(path, b"data").unwrap();
+        }
+
+        let mut output = Vec::new();
+        let summary =
+            super::do_rename(&mut output, &Args::default(), Forward::new(&from, &to)).unwrap();
+        assert_eq!(2, summary.count);
+        assert_eq!(2, summary.unchanged);
+        assert!(to.iter().all(|path| path.exists()));
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(!output.contains("1.txt ->"));
+        assert!(output.ends_with("Moved 2 files (8 B), 2 unchanged\n"));
     }
 }
@@ -0,0 +1,76 @@
+use std::path::{Path, PathBuf};
+
+/// An explicit set of renames read from a two-column `old,new` CSV file
+#[derive(Debug, Default)]
+pub struct Mapping {
+    pub from: Vec<PathBuf>,
+    pub to: Vec<PathBuf>,
+
+    /// Sources named in the file which do not exist
+    pub missing: Vec<PathBuf>,
+}
+
+pub fn read(path: &Path) -> csv::Result<Mapping> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .trim(csv::Trim::All)
+        .from_path(path)?;
+
+    let mut mapping = Mapping::default();
+    for record in reader.deserialize() {
+        let (from, to): (PathBuf, PathBuf) = record?;
+        if from.is_file() {
+            mapping.from.push(from);
+            mapping.to.push(to);
+        } else {
+            mapping.missing.push(from);
+        }
+    }
+
+    Ok(mapping)
+}
+
+/// Write a set of renames as a two-column `old,new` CSV file which `read` accepts
+///
+/// Pairs whose source and target are the same are omitted.
+pub fn write<P: AsRef<Path>>(path: &Path, from: &[P], to: &[P]) -> csv::Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_path(path)?;
+
+    for (from, to) in from.iter().zip(to) {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        if from != to {
+            writer.serialize((from, to))?;
+        }
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    #[test]
+    fn write_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let map = dir.path().join("map.csv");
+
+        let from: Vec<PathBuf> = ["a.txt", "b, c.txt", "same.txt"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let to: Vec<PathBuf> = ["1.txt", "2.txt", "same.txt"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        super::write(&map, &from, &to).unwrap();
+
+        assert_eq!(
+            "a.txt,1.txt\n\"b, c.txt\",2.txt\n",
+            fs::read_to_string(&map).unwrap()
+        );
+    }
+}
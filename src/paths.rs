@@ -1,32 +1,122 @@
-use std::{fs, iter, path::PathBuf};
+use std::{
+    fs, io, iter,
+    path::{Path, PathBuf},
+};
 
-pub fn extract(path: impl AsRef<str>) -> Box<dyn Iterator<Item = PathBuf>> {
+use crate::args::{Args, WalkOrder};
+
+pub fn extract(path: impl AsRef<str>, options: &Args) -> Box<dyn Iterator<Item = PathBuf>> {
     let path = path.as_ref();
-    match fs::metadata(path) {
-        Ok(metadata) => literal_path(path, metadata),
+    let paths = match fs::metadata(path) {
+        Ok(metadata) => literal_path(path, metadata, options),
         Err(_) => glob_pattern(path),
+    };
+
+    let paths = match options.exclude_regex.clone() {
+        Some(exclude) => {
+            Box::new(paths.filter(move |path| !exclude.is_match(&path.to_string_lossy())))
+        }
+        None => paths,
+    };
+
+    // Sidecar files name other files, and are not renamed themselves
+    match options.sidecar.clone() {
+        Some(sidecar) => {
+            Box::new(paths.filter(move |path| path.extension() != Some(sidecar.as_ref())))
+        }
+        None => paths,
     }
 }
 
-fn literal_path(path: &str, metadata: fs::Metadata) -> Box<dyn Iterator<Item = PathBuf>> {
+fn literal_path(
+    path: &str,
+    metadata: fs::Metadata,
+    options: &Args,
+) -> Box<dyn Iterator<Item = PathBuf>> {
     if metadata.is_file() {
         return Box::new(iter::once(path.into()));
     }
 
+    let root = PathBuf::from(path);
+    let only = options.only.clone();
+    let contents_first = matches!(options.walk_order, WalkOrder::ContentsFirst);
     let paths = walkdir::WalkDir::new(path)
-        .contents_first(true)
+        .contents_first(contents_first)
+        .sort_by(move |a, b| {
+            // Within a directory, subdirectories come first when walking contents first
+            let a_dir = a.file_type().is_dir();
+            let b_dir = b.file_type().is_dir();
+            let by_kind = if contents_first {
+                b_dir.cmp(&a_dir)
+            } else {
+                a_dir.cmp(&b_dir)
+            };
+            by_kind.then_with(|| a.file_name().cmp(b.file_name()))
+        })
         .into_iter()
-        .filter_entry(|entry| {
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
             entry
                 .metadata()
                 .map(|meta| meta.file_type().is_file())
                 .unwrap_or_default()
         })
-        .filter_map(|entry| entry.ok().map(|entry| entry.path().into()));
+        .map(|entry| entry.into_path())
+        .filter(move |path| {
+            let only = match &only {
+                Some(only) => only,
+                None => return true,
+            };
+
+            let relative = path.strip_prefix(&root).unwrap_or(path);
+            path.file_name()
+                .is_some_and(|name| only.matches_path(name.as_ref()))
+                || only.matches_path(relative)
+        });
 
     Box::new(paths)
 }
 
+/// The directory which the files found for an input argument are relative to
+///
+/// This is the directory itself for a directory, the parent for a file, and the part of a glob
+/// pattern before its first wildcard.
+pub fn root(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    match fs::metadata(path) {
+        Ok(meta) if meta.is_dir() => path.into(),
+        _ if !is_glob(path.as_os_str().to_string_lossy().as_ref()) => {
+            path.parent().unwrap_or(Path::new("")).into()
+        }
+        _ => path
+            .components()
+            .take_while(|component| !is_glob(&component.as_os_str().to_string_lossy()))
+            .collect(),
+    }
+}
+
+fn is_glob(text: &str) -> bool {
+    text.contains(['*', '?', '['])
+}
+
+/// Read a list of paths separated by NUL bytes, as written by `find -print0`
+///
+/// Empty entries, including the one following a trailing NUL, are ignored.
+pub fn read_list0(path: &Path) -> io::Result<Vec<String>> {
+    fs::read(path)?
+        .split(|&b| b == 0)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            String::from_utf8(entry.to_vec())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .collect()
+}
+
+/// Expand a glob pattern, keeping only files
+///
+/// `**` matches any number of directories, including none, so `images/**/*.jpg` finds files
+/// directly inside `images` as well as in its subdirectories.
 fn glob_pattern(path: &str) -> Box<dyn Iterator<Item = PathBuf>> {
     let paths = match glob::glob(path) {
         Ok(paths) => paths,
@@ -42,3 +132,145 @@ fn glob_pattern(path: &str) -> Box<dyn Iterator<Item = PathBuf>> {
 
     Box::new(paths)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use crate::args::{Args, WalkOrder};
+
+    #[test]
+    fn only_filters_directory_walk() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("nested")).unwrap();
+        for name in ["a.jpg", "b.txt", "nested/c.jpg", "nested/d.png"] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+
+        let options = Args {
+            only: glob::Pattern::new("*.jpg").ok(),
+            ..Default::default()
+        };
+
+        let mut paths: Vec<_> = super::extract(dir.path().to_str().unwrap(), &options).collect();
+        paths.sort();
+
+        let expected: Vec<PathBuf> =
+            vec![dir.path().join("a.jpg"), dir.path().join("nested/c.jpg")];
+        assert_eq!(expected, paths);
+
+        let options = Args {
+            only: glob::Pattern::new("nested/*").ok(),
+            ..Default::default()
+        };
+
+        let paths = super::extract(dir.path().to_str().unwrap(), &options);
+        assert_eq!(2, paths.count());
+    }
+
+    #[test]
+    fn exclude_regex_drops_matching_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a.txt", "b.tmp", "c.txt~", "d.tmp.txt"] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+
+        let options = Args {
+            exclude_regex: Some(regex::Regex::new(r"\.tmp$|~$").unwrap()),
+            ..Default::default()
+        };
+
+        let mut paths: Vec<_> = super::extract(dir.path().to_str().unwrap(), &options).collect();
+        paths.sort();
+        assert_eq!(
+            vec![dir.path().join("a.txt"), dir.path().join("d.tmp.txt")],
+            paths
+        );
+
+        let glob = dir.path().join("b.*");
+        assert_eq!(0, super::extract(glob.to_str().unwrap(), &options).count());
+    }
+
+    #[test]
+    fn read_null_separated_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let list = dir.path().join("list");
+        fs::write(&list, "a.txt\0two\nlines.txt\0\0c.txt\0").unwrap();
+
+        assert_eq!(
+            vec!["a.txt", "two\nlines.txt", "c.txt"],
+            super::read_list0(&list).unwrap()
+        );
+
+        fs::write(&list, b"a.txt\0caf\xe9.txt\0").unwrap();
+        assert!(super::read_list0(&list).is_err());
+    }
+
+    #[test]
+    fn input_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("nested")).unwrap();
+        fs::write(dir.path().join("nested/a.txt"), b"").unwrap();
+
+        let root = |path: PathBuf| super::root(path.to_str().unwrap());
+        assert_eq!(dir.path().join("nested"), root(dir.path().join("nested")));
+        assert_eq!(
+            dir.path().join("nested"),
+            root(dir.path().join("nested/a.txt"))
+        );
+        assert_eq!(dir.path().to_owned(), root(dir.path().join("*/*.txt")));
+        assert_eq!(PathBuf::new(), super::root("*.txt"));
+    }
+
+    #[test]
+    fn recursive_glob_matches_files_only() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        fs::create_dir_all(dir.path().join("dir.txt")).unwrap();
+        for name in ["top.txt", "a/mid.txt", "a/b/deep.txt", "a/b/image.jpg"] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+
+        let pattern = dir.path().join("**/*.txt");
+        let mut paths: Vec<_> =
+            super::extract(pattern.to_str().unwrap(), &Args::default()).collect();
+        paths.sort();
+
+        let expected: Vec<PathBuf> = ["a/b/deep.txt", "a/mid.txt", "top.txt"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        assert_eq!(expected, paths);
+    }
+
+    #[test]
+    fn walk_order() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("m/deep")).unwrap();
+        for name in ["a.txt", "z.txt", "m/b.txt", "m/deep/c.txt"] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+
+        let walk = |walk_order| {
+            let options = Args {
+                walk_order,
+                ..Default::default()
+            };
+            super::extract(dir.path().to_str().unwrap(), &options)
+                .map(|path| path.strip_prefix(dir.path()).unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let expected: Vec<PathBuf> = ["m/deep/c.txt", "m/b.txt", "a.txt", "z.txt"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(expected, walk(WalkOrder::ContentsFirst));
+
+        let expected: Vec<PathBuf> = ["a.txt", "z.txt", "m/b.txt", "m/deep/c.txt"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(expected, walk(WalkOrder::ParentsFirst));
+    }
+}
@@ -1,250 +1,2874 @@
 use std::{
-    fmt::{self, Display},
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    error,
+    ffi::OsString,
+    fmt::{self, Display, Write},
+    fs,
+    io::{self, Read},
     iter,
+    ops::Range,
     path::{Path, PathBuf},
+    process::{Command, Stdio},
 };
 
-use regex::Regex;
+use chrono::{DateTime, Local, Utc};
+use regex::{Regex, RegexBuilder};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
 
 use crate::{
-    args::Args,
-    template::{Segment, Template, TemplateParser},
+    args::{Args, OnEmpty, OnMissingTag},
+    audio::AudioTags,
+    paths,
+    template::{self, Length, Segment, TagField, Template, TemplateParser},
 };
 
+/// Raised when numbering a batch would run past `u32::MAX`
+#[derive(Clone, Debug)]
+pub struct IndexOverflow {
+    pub start: u32,
+    pub count: usize,
+}
+
+impl fmt::Display for IndexOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "numbering {} files from {} would exceed the maximum index ({})",
+            self.count,
+            self.start,
+            u32::MAX
+        )
+    }
+}
+
+impl error::Error for IndexOverflow {}
+
+/// Raised when a file's new name cannot be rendered
+#[derive(Debug)]
+pub enum RenameError {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    NotANumber {
+        path: PathBuf,
+        capture: String,
+    },
+    NotUnicode {
+        path: PathBuf,
+    },
+    NoMatch {
+        path: PathBuf,
+    },
+    MatchIndex {
+        path: PathBuf,
+        index: usize,
+        count: usize,
+    },
+    Separator {
+        path: PathBuf,
+        text: String,
+    },
+    EmptyName {
+        path: PathBuf,
+    },
+    EmptyCapture {
+        path: PathBuf,
+    },
+    MissingTag {
+        path: PathBuf,
+        field: TagField,
+    },
+    Command {
+        path: PathBuf,
+        reason: String,
+    },
+}
+
+impl fmt::Display for RenameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenameError::Io { path, error } => {
+                write!(f, "unable to read file: {}\n  {}", error, path.display())
+            }
+            RenameError::NotANumber { path, capture } => write!(
+                f,
+                "captured text is not a number: {:?}\n  {}",
+                capture,
+                path.display()
+            ),
+            RenameError::NoMatch { path } => {
+                write!(f, "pattern does not match filename\n  {}", path.display())
+            }
+            RenameError::MatchIndex { path, index, count } => write!(
+                f,
+                "match index {} is out of range: pattern matches {} times\n  {}",
+                index,
+                count,
+                path.display()
+            ),
+            RenameError::Separator { path, text } => write!(
+                f,
+                "text taken from the filename contains a path separator: {:?}\n  {}\nTo create directories, put the separator in the template itself",
+                text,
+                path.display()
+            ),
+            RenameError::EmptyName { path } => write!(
+                f,
+                "template renders an empty name; include {{o}} or {{n}} in it\n  {}",
+                path.display()
+            ),
+            RenameError::EmptyCapture { path } => write!(
+                f,
+                "pattern selects an empty name (see --on-empty)\n  {}",
+                path.display()
+            ),
+            RenameError::MissingTag { path, field } => write!(
+                f,
+                "file has no {} tag (see --on-missing-tag)\n  {}",
+                field.name(),
+                path.display()
+            ),
+            RenameError::Command { path, reason } => write!(
+                f,
+                "naming command failed: {} (see --on-exec-failure)\n  {}",
+                reason,
+                path.display()
+            ),
+            RenameError::NotUnicode { path } => write!(
+                f,
+                "filename is not valid UTF-8 (use --lossy-names to rename it anyway)\n  {}",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl error::Error for RenameError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RenameError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// An independent sequence of numbers, used when counting per extension
+#[derive(Debug)]
+struct Counter {
+    idx: u64,
+    count: Option<usize>,
+    last: Option<u64>,
+}
+
 #[derive(Debug)]
 pub struct Renamer {
-    idx: u32,
+    idx: u64,
     count: Option<usize>,
+    start: u64,
+    ext_counters: Option<HashMap<String, Counter>>,
+
+    /// The number given to the last file of the batch, which sets the width of every number
+    last: Option<u64>,
+
+    /// Numbers which are never assigned
+    skip: Vec<Range<usize>>,
     template: Template,
     pattern: Option<Regex>,
+    on_empty: OnEmpty,
+    on_missing_tag: OnMissingTag,
+    replacement: Option<String>,
+    strip_match: bool,
+    prefix: String,
+    suffix: String,
+
+    /// Literal text removed from the ends of the original name before it is rendered
+    strip_prefix: Option<String>,
+    strip_suffix: Option<String>,
+
+    /// Changes applied in turn to the original name, after any stripping
+    transforms: Vec<Transform>,
+    group_sep: char,
+    pad_char: char,
+    min_width: usize,
+    now: DateTime<Local>,
+    keep_extension: bool,
+    match_path: bool,
+    lossy_names: bool,
+    ascii: bool,
+    out_dir: Option<PathBuf>,
+
+    /// Input roots, against which each file's relative directory is found for --parents and {pp}
+    roots: Option<Vec<PathBuf>>,
+    parents: bool,
+
+    /// Replaces path separators in the relative directory rendered by {pp}
+    path_sep: char,
+
+    /// Directory arguments, below which {depth} is measured
+    dirs: Option<Vec<PathBuf>>,
+    continue_numbering: bool,
+
+    /// Matches names already rendered by the template, capturing their number
+    index_pattern: Option<Regex>,
+
+    /// Numbers cycle through this many values, from the start value, with --modulo
+    modulo: Option<u64>,
+
+    /// Which match of the pattern to use, counting from zero
+    match_index: usize,
+
+    /// The last timestamp rendered, so that the next can be made later
+    last_timestamp: Option<i64>,
+
+    /// A pattern applied to the original extension, and its replacement
+    ext_replacement: Option<(Regex, String)>,
+
+    /// Replace the extension with one matching the file's content, where it can be identified
+    detect_extension: bool,
+
+    /// Lowercase the final filename and join its words with this separator
+    normalize: Option<char>,
+
+    /// Replace runs of whitespace in the new stem with a single space
+    collapse_spaces: bool,
+
+    /// The extension of sidecar files which give a file's new stem
+    sidecar: Option<String>,
+
+    /// A shell command whose output is used as each file's new stem
+    exec: Option<String>,
+
+    /// Restart numbering when this capture group changes, and the value it had for the last file
+    reset_group: Option<String>,
+    last_group: Option<String>,
+
+    /// Size numbers to the largest number captured by the pattern, rather than to the batch
+    width_from_capture: bool,
+    capture_max: Option<usize>,
+
+    /// Pad captured numbers to the width of the widest one in the batch
+    zero_pad_original: bool,
+    capture_width: Option<usize>,
 }
 
 impl Renamer {
-    pub fn new(options: &mut Args, count: Option<usize>) -> Self {
+    pub fn new(options: &mut Args, count: Option<usize>) -> anyhow::Result<Self> {
+        if let Some(count) = count {
+            check_index_range(options.start, count)?;
+        }
+
+        let group_sep = options.group_sep.unwrap_or(',');
+        if std::path::is_separator(group_sep) {
+            anyhow::bail!(
+                "digit group separator cannot be a path separator: {}",
+                group_sep
+            );
+        }
+
+        let pad_char = options.pad_char.unwrap_or('0');
+        if std::path::is_separator(pad_char) {
+            anyhow::bail!("padding character cannot be a path separator: {}", pad_char);
+        }
+
+        let path_sep = options.path_sep_replacement.unwrap_or('_');
+        if std::path::is_separator(path_sep) {
+            anyhow::bail!(
+                "path separator replacement cannot be a path separator: {}",
+                path_sep
+            );
+        }
+
+        if let Some(sep) = options
+            .normalize
+            .filter(|&sep| std::path::is_separator(sep))
+        {
+            anyhow::bail!("normalize separator cannot be a path separator: {}", sep);
+        }
+
         let parser = TemplateParser::new();
-        Self {
-            idx: options.start,
-            count,
-            template: parser.parse(&options.template),
-            pattern: options.pattern.take(),
+        let template = match &options.format {
+            Some(format) => parser.parse_format(format),
+            None => parser.parse(&options.template)?,
+        };
+        template.check_groups(options.pattern.as_ref())?;
+        if let Some(name) = &options.reset_on_change {
+            template::check_group(options.pattern.as_ref(), name)?;
         }
-    }
 
-    pub fn rename(&mut self, path: &Path) -> PathBuf {
-        let stem = self.context(path).to_string();
-        let mut result = path.with_file_name(stem);
+        let prefix = options.prefix.clone().unwrap_or_default();
+        let suffix = options.suffix.clone().unwrap_or_default();
+        let index_pattern = template.numeric_pattern().map(|pattern| {
+            let pattern = format!(
+                "^{}{}{}$",
+                regex::escape(&prefix),
+                pattern,
+                regex::escape(&suffix)
+            );
+            Regex::new(&pattern).expect("template patterns are valid")
+        });
 
-        if let Some(extension) = path.extension() {
-            result.set_extension(extension);
+        if options.insert_at.is_some() && index_pattern.is_none() {
+            anyhow::bail!("--insert-at requires a template with a sequence number, e.g. {{n}}");
         }
 
-        self.idx += 1;
-        result
+        let pattern = match options.pattern.take() {
+            Some(pattern) if options.pattern_ignore_case => Some(
+                RegexBuilder::new(pattern.as_str())
+                    .case_insensitive(true)
+                    .build()?,
+            ),
+            pattern => pattern,
+        };
+
+        let roots = (options.parents || template.uses_parent_path())
+            .then(|| options.paths.iter().map(|path| paths::root(path)).collect());
+        let dirs = template.uses_depth().then(|| {
+            options
+                .paths
+                .iter()
+                .map(PathBuf::from)
+                .filter(|path| path.is_dir())
+                .collect()
+        });
+
+        let last =
+            count.map(|count| last_index(options.start.into(), count, &options.skip_numbers));
+
+        Ok(Self {
+            idx: options.start.into(),
+            count,
+            start: options.start.into(),
+            ext_counters: options.counter_per_ext.then(HashMap::new),
+            last,
+            skip: options.skip_numbers.clone(),
+            template,
+            pattern,
+            on_empty: options.on_empty,
+            on_missing_tag: options.on_missing_tag,
+            replacement: options.replacement.clone(),
+            strip_match: options.strip_match,
+            prefix,
+            suffix,
+            strip_prefix: options.strip_prefix.clone(),
+            strip_suffix: options.strip_suffix.clone(),
+            transforms: options.transform.clone(),
+            group_sep,
+            pad_char,
+            min_width: options.min_width,
+            now: Local::now(),
+            keep_extension: !options.no_extension,
+            match_path: options.match_path,
+            lossy_names: options.lossy_names,
+            ascii: options.ascii,
+            out_dir: options.out_dir.clone(),
+            roots,
+            parents: options.parents,
+            path_sep,
+            dirs,
+            continue_numbering: options.continue_numbering,
+            index_pattern,
+            modulo: options.modulo.map(u64::from),
+            match_index: options.match_index,
+            last_timestamp: None,
+            ext_replacement: options.ext_pattern.take().zip(options.ext_replace.clone()),
+            detect_extension: options.detect_extension,
+            normalize: options.normalize,
+            collapse_spaces: options.collapse_spaces,
+            sidecar: options.sidecar.clone(),
+            exec: options.exec.clone(),
+            reset_group: options.reset_on_change.clone(),
+            last_group: None,
+            width_from_capture: options.width_from_capture,
+            capture_max: None,
+            zero_pad_original: options.zero_pad_original,
+            capture_width: None,
+        })
     }
 
-    fn context<'p>(&'p self, path: &'p Path) -> RenameContext {
-        RenameContext {
-            idx: self.idx,
-            width: get_width(self.count),
-            path,
-            template: &self.template,
-            pattern: self.pattern.as_ref(),
+    /// Examine the whole batch before renaming any of it
+    ///
+    /// Options which depend on the full set of files, like counting per extension, need this to
+    /// size their numbers correctly.
+    pub fn scan(&mut self, paths: &[impl AsRef<Path>]) -> io::Result<()> {
+        if self.continue_numbering {
+            if let Some(max) = self.highest_existing_index(paths)? {
+                self.start = self.start.max(max + 1);
+                self.idx = self.start;
+                self.last = self
+                    .count
+                    .map(|count| last_index(self.start, count, &self.skip));
+            }
+        }
+
+        if self.width_from_capture {
+            self.capture_max = paths
+                .iter()
+                .filter_map(|path| {
+                    let text = self.match_text(path.as_ref());
+                    self.capture(&text)?.parse::<usize>().ok()
+                })
+                .max();
+        }
+
+        if self.zero_pad_original {
+            self.capture_width = paths
+                .iter()
+                .filter_map(|path| {
+                    let text = self.match_text(path.as_ref());
+                    self.capture(&text)
+                        .filter(|capture| is_number(capture))
+                        .map(str::len)
+                })
+                .max();
+        }
+
+        if let Some(counters) = &mut self.ext_counters {
+            for path in paths {
+                let counter = counters
+                    .entry(extension_key(path.as_ref()))
+                    .or_insert(Counter {
+                        idx: self.start,
+                        count: None,
+                        last: None,
+                    });
+                counter.count = Some(counter.count.unwrap_or_default() + 1);
+            }
+            for counter in counters.values_mut() {
+                counter.last = counter
+                    .count
+                    .map(|count| last_index(self.start, count, &self.skip));
+            }
         }
+        Ok(())
     }
-}
 
-pub struct RenameContext<'a> {
-    idx: u32,
-    width: Option<usize>,
-    path: &'a Path,
-    template: &'a Template,
-    pattern: Option<&'a Regex>,
-}
+    /// Find the highest number among existing files in the destination which match the template
+    ///
+    /// Files in the batch itself are ignored, since they are about to be renamed.
+    fn highest_existing_index(&self, paths: &[impl AsRef<Path>]) -> io::Result<Option<u64>> {
+        if self.index_pattern.is_none() {
+            return Ok(None);
+        }
 
-impl RenameContext<'_> {
-    fn format_filename(&self, f: &mut fmt::Formatter, width: usize) -> fmt::Result {
-        let name = self
-            .path
-            .file_stem()
-            .expect("Must be a filename")
-            .to_string_lossy();
+        let batch: HashSet<_> = paths.iter().map(|path| path.as_ref()).collect();
+        let dirs: HashSet<_> = match &self.out_dir {
+            Some(dir) => iter::once(dir.as_path()).collect(),
+            None => batch
+                .iter()
+                .map(|path| path.parent().unwrap_or(Path::new("")))
+                .collect(),
+        };
+
+        let mut max = None;
+        for dir in dirs {
+            let read_dir = if dir.as_os_str().is_empty() {
+                fs::read_dir(".")
+            } else {
+                fs::read_dir(dir)
+            };
+            let entries = match read_dir {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
 
-        let name = self.extract_name(&name);
-        match width {
-            1 => f.write_str(name),
-            n => f.write_str(&name[..n]),
+            for entry in entries {
+                let path = dir.join(entry?.file_name());
+                if batch.contains(path.as_path()) {
+                    continue;
+                }
+
+                max = max.max(self.existing_index(&path));
+            }
         }
+        Ok(max)
     }
 
-    fn extract_name<'a>(&self, text: &'a str) -> &'a str {
-        self.pattern
-            .and_then(|x| x.captures(text))
-            .and_then(|x| x.get(1).or_else(|| x.get(0)))
-            .map_or(text, |x| x.as_str())
+    /// The number of a file already named by this template, if it is one
+    fn existing_index(&self, path: &Path) -> Option<u64> {
+        let name = if self.keep_extension {
+            path.file_stem()
+        } else {
+            path.file_name()
+        };
+
+        let captures = self.index_pattern.as_ref()?.captures(name?.to_str()?)?;
+        captures[1].parse().ok()
     }
-}
 
-impl Display for RenameContext<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for segment in self.template.segments() {
-            match segment {
-                Segment::Literal(s) => f.write_str(s)?,
-                Segment::Numeric(width) => write!(
-                    f,
-                    "{:0width$}",
-                    self.idx,
-                    width = width.max(&self.width.unwrap_or_default())
-                )?,
-                Segment::Filename(width) => self.format_filename(f, *width)?,
+    /// Order a batch so that new files are numbered from `position`, after an existing sequence
+    ///
+    /// Files already named by the template keep their relative order, sorted by number; files
+    /// which are not are inserted as a group at `position`, so later files shift up to make room.
+    pub fn insert_at(&self, paths: Vec<PathBuf>, position: u32) -> Vec<PathBuf> {
+        let (mut numbered, new): (Vec<_>, Vec<_>) = paths
+            .into_iter()
+            .map(|path| (self.existing_index(&path), path))
+            .partition(|(index, _)| index.is_some());
+        numbered.sort_by_key(|(index, _)| *index);
+
+        let slot = (u64::from(position).saturating_sub(self.start) as usize).min(numbered.len());
+        let after = numbered.split_off(slot);
+        numbered
+            .into_iter()
+            .chain(new)
+            .chain(after)
+            .map(|(_, path)| path)
+            .collect()
+    }
+
+    pub fn rename(&mut self, path: &Path) -> Result<PathBuf, RenameError> {
+        let stem = match self.read_sidecar(path)? {
+            Some(stem) => stem,
+            None => match &self.exec {
+                Some(command) => run_command(command, path)?,
+                None => self.render_stem(path)?,
+            },
+        };
+
+        let mut result = match &self.out_dir {
+            Some(dir) if self.parents => dir.join(self.relative_dir(path)).join(stem),
+            Some(dir) => dir.join(stem),
+            None => path.with_file_name(stem),
+        };
+
+        let detected = if self.detect_extension {
+            infer::get_from_path(path)
+                .map_err(|error| RenameError::Io {
+                    path: path.into(),
+                    error,
+                })?
+                .map(|kind| kind.extension())
+        } else {
+            None
+        };
+
+        if let Some(extension) = detected {
+            result.set_extension(extension);
+        } else if self.keep_extension {
+            if let Some(extension) = path.extension() {
+                match &self.ext_replacement {
+                    Some((pattern, replacement)) => {
+                        let extension = extension.to_string_lossy();
+                        result
+                            .set_extension(&*pattern.replace_all(&extension, replacement.as_str()));
+                    }
+                    None => {
+                        result.set_extension(extension);
+                    }
+                }
             }
         }
-        Ok(())
+
+        if let Some(sep) = self.normalize {
+            if let Some(name) = result.file_name() {
+                let name = normalize(&name.to_string_lossy(), sep);
+                result.set_file_name(name);
+            }
+        }
+
+        Ok(result)
     }
-}
 
-fn get_width(count: Option<usize>) -> Option<usize> {
-    let count = count?;
-    let mut witness_pairs = iter::successors(Some((1usize, 10usize)), |(width, witness)| {
-        witness.checked_mul(10).map(|witness| (width + 1, witness))
-    });
+    /// Render the template, with any prefix and suffix, for the next file
+    fn render_stem(&mut self, path: &Path) -> Result<String, RenameError> {
+        if !self.lossy_names && self.template.uses_name() {
+            let name = if self.match_path {
+                Some(path.as_os_str())
+            } else {
+                path.file_stem()
+            };
 
-    witness_pairs.find_map(|witness| {
-        if witness.1 > count {
-            Some(witness.0)
+            if name.and_then(|name| name.to_str()).is_none() {
+                return Err(RenameError::NotUnicode { path: path.into() });
+            }
+        }
+
+        self.check_match_index(path)?;
+        if self.on_empty != OnEmpty::KeepOriginal && self.empty_capture(path) {
+            return Err(RenameError::EmptyCapture { path: path.into() });
+        }
+        self.reset_on_change(path);
+
+        let (idx, last) = self.next_index(path);
+        let (idx, count) = match self.modulo {
+            // Pad to the widest number in the cycle rather than to the size of the batch
+            Some(modulo) => {
+                let last = self.start + modulo - 1;
+                (
+                    self.start + (idx - self.start) % modulo,
+                    Some(last as usize),
+                )
+            }
+            // Pad to the last number of the sequence, which depends on where it starts
+            None => (idx, self.capture_max.or(last.map(|last| last as usize))),
+        };
+        let timestamp = if self.template.uses_timestamp() {
+            Some(self.next_timestamp())
         } else {
             None
-        }
-    })
-}
+        };
+        let groups = self.capture_groups(path)?;
+        let lines = if self.template.uses_line_count() {
+            Some(count_lines(path).map_err(|error| RenameError::Io {
+                path: path.into(),
+                error,
+            })?)
+        } else {
+            None
+        };
 
-#[cfg(test)]
-mod tests {
-    use std::path::Path;
+        let tags = self.audio_tags(path)?;
+        let modified = if self.template.uses_modified() {
+            let modified = path
+                .metadata()
+                .and_then(|meta| meta.modified())
+                .map_err(|error| RenameError::Io {
+                    path: path.into(),
+                    error,
+                })?;
+            Some(DateTime::<Local>::from(modified))
+        } else {
+            None
+        };
 
-    use crate::template::TemplateParser;
+        let mut context = self.context(path, idx, count, groups);
+        context.lines = lines;
+        context.tags = tags;
+        context.modified = modified;
+        context.timestamp = timestamp;
+        context.depth = self.depth(path);
+        context.parent_path = self.parent_path(path);
+        context.validate()?;
 
-    #[test]
-    fn rename_works() {
-        let files = &[
-            "f4240.jpg",
-            "f4241.jpg",
-            "f4242.jpg",
-            "f4243.jpg",
-            "f4244.jpg",
-            "f4245.jpg",
-            "f4246.jpg",
-            "f4247.jpg",
-            "f4248.jpg",
-            "f4249.jpg",
-        ];
+        let mut stem = format!("{}{}{}", self.prefix, context, self.suffix);
+        if self.collapse_spaces {
+            stem = collapse_spaces(&stem);
+        }
+        if stem.trim().is_empty() {
+            return Err(RenameError::EmptyName { path: path.into() });
+        }
+        Ok(stem)
+    }
 
-        let expected = &[
-            Path::new("Fuzzy Bear 001-f42 (original).jpg"),
-            Path::new("Fuzzy Bear 002-f42 (original).jpg"),
-            Path::new("Fuzzy Bear 003-f42 (original).jpg"),
-            Path::new("Fuzzy Bear 004-f42 (original).jpg"),
-            Path::new("Fuzzy Bear 005-f42 (original).jpg"),
-            Path::new("Fuzzy Bear 006-f42 (original).jpg"),
-            Path::new("Fuzzy Bear 007-f42 (original).jpg"),
-            Path::new("Fuzzy Bear 008-f42 (original).jpg"),
-            Path::new("Fuzzy Bear 009-f42 (original).jpg"),
-            Path::new("Fuzzy Bear 010-f42 (original).jpg"),
-        ];
+    /// The stem given by a sidecar file, e.g. `photo.jpg.name` for `photo.jpg`, if there is one
+    ///
+    /// A file named by its sidecar does not take a number from the sequence.
+    fn read_sidecar(&self, path: &Path) -> Result<Option<String>, RenameError> {
+        let Some(extension) = &self.sidecar else {
+            return Ok(None);
+        };
 
-        let parser = TemplateParser::new();
-        let mut renamer = super::Renamer {
-            idx: 1,
-            count: None,
-            template: parser.parse("Fuzzy Bear {n:3}-{o:3} (original)"),
-            pattern: None,
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(".");
+        sidecar.push(extension);
+        let text = match fs::read_to_string(&sidecar) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => {
+                return Err(RenameError::Io {
+                    path: sidecar.into(),
+                    error,
+                })
+            }
         };
 
-        let actual = files
-            .into_iter()
-            .cloned()
-            .map(|x| renamer.rename(x.as_ref()));
+        // An empty sidecar names nothing, so the template applies
+        let stem = text.trim();
+        if stem.is_empty() {
+            return Ok(None);
+        }
+        if stem.chars().any(std::path::is_separator) {
+            return Err(RenameError::Separator {
+                path: path.into(),
+                text: stem.into(),
+            });
+        }
+        Ok(Some(stem.into()))
+    }
 
-        for (actual, &expected) in actual.zip(expected) {
-            assert_eq!(actual, expected);
+    /// The directory of a file relative to the input it was found under, with --parents
+    ///
+    /// When more than one input contains the file, the most specific one wins.
+    fn relative_dir<'p>(&self, path: &'p Path) -> &'p Path {
+        let roots = match &self.roots {
+            Some(roots) => roots,
+            None => return Path::new(""),
+        };
+
+        roots
+            .iter()
+            .filter_map(|root| path.strip_prefix(root).ok())
+            .min_by_key(|relative| relative.components().count())
+            .and_then(Path::parent)
+            .unwrap_or(Path::new(""))
+    }
+
+    /// The relative directory of a file, with its separators replaced, for {pp}
+    fn parent_path(&self, path: &Path) -> String {
+        if !self.template.uses_parent_path() {
+            return String::new();
         }
+
+        let components: Vec<_> = self
+            .relative_dir(path)
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect();
+        components.join(&self.path_sep.to_string())
     }
 
-    #[test]
-    fn rename_works_with_offset_idx() {
-        let files = &[
-            "f4240.jpg",
-            "f4241.jpg",
-            "f4242.jpg",
-            "f4243.jpg",
-            "f4244.jpg",
-            "f4245.jpg",
-            "f4246.jpg",
-            "f4247.jpg",
-            "f4248.jpg",
-            "f4249.jpg",
-        ];
+    /// How many directories deep a file is below the directory argument it was found in
+    ///
+    /// This matches the depth of the walk, so a file directly inside the directory is at depth 1.
+    /// Files given directly or by glob are at depth 0.
+    fn depth(&self, path: &Path) -> usize {
+        self.dirs
+            .iter()
+            .flatten()
+            .filter_map(|dir| path.strip_prefix(dir).ok())
+            .map(|relative| relative.components().count())
+            .min()
+            .unwrap_or_default()
+    }
 
-        let expected = &[
-            Path::new("Fuzzy Bear 021-f42 (original).jpg"),
-            Path::new("Fuzzy Bear 022-f42 (original).jpg"),
-            Path::new("Fuzzy Bear 023-f42 (original).jpg"),
-            Path::new("Fuzzy Bear 024-f42 (original).jpg"),
-            Path::new("Fuzzy Bear 025-f42 (original).jpg"),
-            Path::new("Fuzzy Bear 026-f42 (original).jpg"),
-            Path::new("Fuzzy Bear 027-f42 (original).jpg"),
-            Path::new("Fuzzy Bear 028-f42 (original).jpg"),
-            Path::new("Fuzzy Bear 029-f42 (original).jpg"),
-            Path::new("Fuzzy Bear 030-f42 (original).jpg"),
-        ];
+    /// Restart numbering if the --reset-on-change group differs from the last file's
+    ///
+    /// A file the pattern does not match counts as an empty value.
+    fn reset_on_change(&mut self, path: &Path) {
+        let (pattern, name) = match (&self.pattern, &self.reset_group) {
+            (Some(pattern), Some(name)) => (pattern, name),
+            _ => return,
+        };
 
-        let parser = TemplateParser::new();
-        let mut renamer = super::Renamer {
-            idx: 21,
-            count: None,
-            template: parser.parse("Fuzzy Bear {n:3}-{o:3} (original)"),
-            pattern: None,
+        let text = self.match_text(path);
+        let value = pattern
+            .captures_iter(&text)
+            .nth(self.match_index)
+            .and_then(|captures| match name.parse::<usize>() {
+                Ok(idx) => captures.get(idx),
+                Err(_) => captures.name(name),
+            })
+            .map(|value| value.as_str())
+            .unwrap_or_default();
+
+        if self.last_group.as_deref().is_some_and(|last| last != value) {
+            self.idx = self.start;
+        }
+        self.last_group = Some(value.into());
+    }
+
+    /// The current time in milliseconds, or one more than the last timestamp if that is later
+    fn next_timestamp(&mut self) -> i64 {
+        let now = Utc::now().timestamp_millis();
+        let timestamp = match self.last_timestamp {
+            Some(last) if last >= now => last + 1,
+            _ => now,
         };
+        self.last_timestamp = Some(timestamp);
+        timestamp
+    }
 
-        let actual = files
-            .into_iter()
-            .cloned()
-            .map(|x| renamer.rename(x.as_ref()));
+    /// Take the next index for this file, along with the last index of the sequence it belongs to
+    fn next_index(&mut self, path: &Path) -> (u64, Option<u64>) {
+        let (idx, last) = match &mut self.ext_counters {
+            Some(counters) => {
+                let counter = counters.entry(extension_key(path)).or_insert(Counter {
+                    idx: self.start,
+                    count: None,
+                    last: None,
+                });
+                (&mut counter.idx, counter.last)
+            }
+            None => (&mut self.idx, self.last),
+        };
 
-        for (actual, &expected) in actual.zip(expected) {
-            assert_eq!(actual, expected);
+        while is_skipped(&self.skip, *idx) {
+            *idx += 1;
         }
+        *idx += 1;
+        (*idx - 1, last)
     }
 
-    #[test]
-    fn rename_works_with_filename_pattern() {
-        let files = &[
-            "Highlander S05E01 Prophecy.mp4",
-            "Highlander S04E22 One Minute to Midnight.mp4",
-        ];
+    fn context<'p>(
+        &'p self,
+        path: &'p Path,
+        idx: u64,
+        count: Option<usize>,
+        groups: HashMap<&'p str, String>,
+    ) -> RenameContext<'p> {
+        RenameContext {
+            idx,
+            width: get_width(count),
+            path,
+            name: self.pad_original(self.extract_name(path)),
+            stem: self.stem(path),
+            groups,
+            lines: None,
+            tags: HashMap::new(),
+            timestamp: None,
+            depth: 0,
+            parent_path: String::new(),
+            group_sep: self.group_sep,
+            pad_char: self.pad_char,
+            min_width: self.min_width,
+            template: &self.template,
+            now: &self.now,
+            modified: None,
+        }
+    }
 
-        let expected = &[
-            Path::new("S05E01 Prophecy.mp4"),
-            Path::new("S05E02 One Minute to Midnight.mp4"),
-        ];
+    /// Look up the capture groups referenced by the template
+    fn capture_groups(&self, path: &Path) -> Result<HashMap<&str, String>, RenameError> {
+        let names: Vec<_> = self
+            .template
+            .segments()
+            .filter_map(|segment| match segment {
+                Segment::Group(name) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
 
-        let parser = TemplateParser::new();
-        let mut renamer = super::Renamer {
-            idx: 1,
-            count: None,
-            template: parser.parse("S05E{0:2} {f}"),
-            pattern: regex::Regex::new(r#".*S\d\dE\d\d (.+)"#).ok(),
+        // Groups are checked against the pattern when the template is loaded
+        let pattern = match &self.pattern {
+            Some(pattern) if !names.is_empty() => pattern,
+            _ => return Ok(HashMap::new()),
         };
 
-        let actual = files
+        let text = self.match_text(path);
+        let captures = pattern
+            .captures_iter(&text)
+            .nth(self.match_index)
+            .ok_or_else(|| RenameError::NoMatch { path: path.into() })?;
+
+        Ok(names
             .into_iter()
-            .cloned()
-            .map(|x| renamer.rename(x.as_ref()));
+            .map(|name| {
+                let value = match name.parse::<usize>() {
+                    Ok(idx) => captures.get(idx),
+                    Err(_) => captures.name(name),
+                };
+                (name, value.map(|x| x.as_str().into()).unwrap_or_default())
+            })
+            .collect())
+    }
 
-        for (actual, &expected) in actual.zip(expected) {
-            assert_eq!(actual, expected);
+    /// Ensure that a match index other than the first refers to a match which exists
+    fn check_match_index(&self, path: &Path) -> Result<(), RenameError> {
+        let pattern = match &self.pattern {
+            Some(pattern) if self.match_index > 0 => pattern,
+            _ => return Ok(()),
+        };
+
+        let count = pattern.find_iter(&self.match_text(path)).count();
+        if self.match_index >= count {
+            return Err(RenameError::MatchIndex {
+                path: path.into(),
+                index: self.match_index,
+                count,
+            });
+        }
+        Ok(())
+    }
+
+    /// The text the pattern is matched against: the file stem, or the full path with --match-path
+    fn match_text<'p>(&self, path: &'p Path) -> Cow<'p, str> {
+        if self.match_path {
+            path.to_string_lossy()
+        } else {
+            path.file_stem()
+                .expect("Must be a filename")
+                .to_string_lossy()
+        }
+    }
+
+    /// The text of each tag field used by the template, read from the file's ID3 tag
+    fn audio_tags(&self, path: &Path) -> Result<HashMap<TagField, String>, RenameError> {
+        let mut fields = HashMap::new();
+        if !self.template.uses_tags() {
+            return Ok(fields);
+        }
+
+        let tags = AudioTags::read(path).map_err(|error| RenameError::Io {
+            path: path.into(),
+            error,
+        })?;
+        for segment in self.template.segments() {
+            if let Segment::Tag(field) = *segment {
+                let text = match tags.get(field) {
+                    Some(text) => text,
+                    None if self.on_missing_tag == OnMissingTag::Empty => String::new(),
+                    None => {
+                        return Err(RenameError::MissingTag {
+                            path: path.into(),
+                            field,
+                        })
+                    }
+                };
+                fields.insert(field, text);
+            }
+        }
+        Ok(fields)
+    }
+
+    /// True if the pattern selects nothing from a file whose name the template uses
+    pub fn empty_capture(&self, path: &Path) -> bool {
+        self.pattern.is_some()
+            && self
+                .template
+                .segments()
+                .any(|segment| matches!(segment, Segment::Filename(_)))
+            && self.extract_raw_name(path).is_empty()
+    }
+
+    /// Extract the portion of the original name selected by the pattern, if any
+    fn extract_name(&self, path: &Path) -> String {
+        let mut name = self.extract_raw_name(path);
+        if name.is_empty() && self.on_empty == OnEmpty::KeepOriginal {
+            name = self.stem(path);
+        }
+        if let Some(stripped) = self
+            .strip_prefix
+            .as_ref()
+            .and_then(|prefix| name.strip_prefix(prefix.as_str()))
+        {
+            name = stripped.into();
+        }
+        if let Some(stripped) = self
+            .strip_suffix
+            .as_ref()
+            .and_then(|suffix| name.strip_suffix(suffix.as_str()))
+        {
+            name = stripped.into();
+        }
+        for transform in &self.transforms {
+            name = transform.apply(&name);
+        }
+
+        if self.ascii {
+            to_ascii(&name)
+        } else {
+            name
+        }
+    }
+
+    /// Pad a number selected by the pattern to the width of the widest in the batch
+    fn pad_original(&self, name: String) -> String {
+        match self.capture_width {
+            Some(width) if is_number(&name) => format!("{:0>width$}", name),
+            _ => name,
+        }
+    }
+
+    /// The original file stem, ignoring any pattern
+    fn stem(&self, path: &Path) -> String {
+        let stem = path
+            .file_stem()
+            .expect("Must be a filename")
+            .to_string_lossy();
+        if self.ascii {
+            to_ascii(&stem)
+        } else {
+            stem.into_owned()
+        }
+    }
+
+    fn extract_raw_name(&self, path: &Path) -> String {
+        let stem = path
+            .file_stem()
+            .expect("Must be a filename")
+            .to_string_lossy();
+
+        if let Some(pattern) = self.pattern.as_ref().filter(|_| self.strip_match) {
+            let stripped = pattern.replace_all(&stem, "");
+            return stripped.split_whitespace().collect::<Vec<_>>().join(" ");
+        }
+
+        if let (Some(pattern), Some(replacement)) = (&self.pattern, &self.replacement) {
+            return pattern
+                .replace_all(&stem, replacement.as_str())
+                .into_owned();
+        }
+
+        self.capture(&self.match_text(path)).unwrap_or(&stem).into()
+    }
+
+    fn capture<'a>(&self, text: &'a str) -> Option<&'a str> {
+        self.pattern
+            .as_ref()
+            .and_then(|x| x.captures_iter(text).nth(self.match_index))
+            .and_then(|x| x.get(1).or_else(|| x.get(0)))
+            .map(|x| x.as_str())
+    }
+}
+
+pub struct RenameContext<'a> {
+    idx: u64,
+    width: Option<usize>,
+    path: &'a Path,
+    name: String,
+    stem: String,
+    groups: HashMap<&'a str, String>,
+    lines: Option<u64>,
+    tags: HashMap<TagField, String>,
+    timestamp: Option<i64>,
+    depth: usize,
+    parent_path: String,
+    group_sep: char,
+    pad_char: char,
+    min_width: usize,
+    template: &'a Template,
+    now: &'a DateTime<Local>,
+    modified: Option<DateTime<Local>>,
+}
+
+impl RenameContext<'_> {
+    /// Check that every segment of the template can be rendered for this file
+    ///
+    /// Directories may be created by separators in the template, but not by text taken from the
+    /// filename, which would scatter files by accident.
+    fn validate(&self) -> Result<(), RenameError> {
+        for segment in self.template.segments() {
+            let text = match segment {
+                Segment::CapturedNumber(_) => {
+                    if !is_number(&self.name) {
+                        return Err(RenameError::NotANumber {
+                            path: self.path.into(),
+                            capture: self.name.clone(),
+                        });
+                    }
+                    continue;
+                }
+                Segment::Filename(_) => &self.name,
+                Segment::Group(name) => &self.groups[name.as_str()],
+                Segment::Tag(field) => &self.tags[field],
+                _ => continue,
+            };
+
+            if text.chars().any(std::path::is_separator) {
+                return Err(RenameError::Separator {
+                    path: self.path.into(),
+                    text: text.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn format_filename(&self, f: &mut fmt::Formatter, name: &str, length: Length) -> fmt::Result {
+        match length {
+            Length::Chars(1) => f.write_str(name),
+            Length::Chars(n) => name.chars().take(n).try_for_each(|c| f.write_char(c)),
+            Length::Words(n) => {
+                let words: Vec<_> = name.split_whitespace().collect();
+                let count = n.unsigned_abs().min(words.len());
+                let words = if n < 0 {
+                    &words[words.len() - count..]
+                } else {
+                    &words[..count]
+                };
+                f.write_str(&words.join(" "))
+            }
+        }
+    }
+
+    fn format_number(
+        &self,
+        f: &mut fmt::Formatter,
+        value: u64,
+        width: usize,
+        grouped: bool,
+    ) -> fmt::Result {
+        let width = width
+            .max(self.width.unwrap_or_default())
+            .max(self.min_width);
+
+        // Zeros are digits, so they are grouped along with the number; other fill is not
+        if self.pad_char == '0' {
+            let digits = format!("{:0width$}", value, width = width);
+            return if grouped {
+                f.write_str(&group_digits(&digits, self.group_sep))
+            } else {
+                f.write_str(&digits)
+            };
+        }
+
+        let mut text = value.to_string();
+        if grouped {
+            text = group_digits(&text, self.group_sep);
+        }
+        for _ in text.chars().count()..width {
+            f.write_char(self.pad_char)?;
+        }
+        f.write_str(&text)
+    }
+}
+
+impl Display for RenameContext<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for segment in self.template.segments() {
+            match segment {
+                Segment::Literal(s) => f.write_str(s)?,
+                Segment::Numeric(width, grouped) => {
+                    self.format_number(f, self.idx, *width, *grouped)?
+                }
+                Segment::ZeroBased(width, grouped) => {
+                    self.format_number(f, self.idx.saturating_sub(1), *width, *grouped)?
+                }
+                Segment::Filename(length) => self.format_filename(f, &self.name, *length)?,
+                Segment::RawFilename(length) => self.format_filename(f, &self.stem, *length)?,
+                Segment::CapturedNumber(width) => {
+                    write!(f, "{:0>width$}", self.name, width = width)?
+                }
+                Segment::Group(name) => f.write_str(&self.groups[name.as_str()])?,
+                Segment::LineCount => write!(f, "{}", self.lines.unwrap_or_default())?,
+                Segment::Now(format) => write!(f, "{}", self.now.format(format))?,
+                Segment::Modified(format) => {
+                    if let Some(modified) = &self.modified {
+                        write!(f, "{}", modified.format(format))?
+                    }
+                }
+                Segment::Timestamp => write!(f, "{}", self.timestamp.unwrap_or_default())?,
+                Segment::Depth => write!(f, "{}", self.depth)?,
+                Segment::Tag(field) => f.write_str(&self.tags[field])?,
+                Segment::ParentPath => f.write_str(&self.parent_path)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Give every target shared by more than one file a numeric suffix, e.g. "photo (1).jpg"
+///
+/// Targets which are already unique are left alone. Suffixes skip any name already taken by
+/// another target.
+pub fn number_collisions(targets: &mut [PathBuf]) {
+    let mut counts = HashMap::new();
+    for target in targets.iter() {
+        *counts.entry(target.clone()).or_insert(0) += 1;
+    }
+
+    let mut taken: HashSet<_> = targets.iter().cloned().collect();
+    let mut next = HashMap::new();
+
+    for target in targets.iter_mut() {
+        if counts[target] < 2 {
+            continue;
+        }
+
+        let n = next.entry(target.clone()).or_insert(1);
+        let candidate = loop {
+            let candidate = with_number(target, *n);
+            *n += 1;
+            if !taken.contains(&candidate) {
+                break candidate;
+            }
+        };
+
+        taken.insert(candidate.clone());
+        *target = candidate;
+    }
+}
+
+fn with_number(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.file_stem().map(OsString::from).unwrap_or_default();
+    name.push(format!(" ({})", n));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// Count the lines in a file without reading it into memory all at once
+///
+/// A final line without a trailing newline still counts.
+fn count_lines(path: &Path) -> io::Result<u64> {
+    let mut file = fs::File::open(path)?;
+    let mut buf = [0; 8192];
+    let mut lines = 0;
+    let mut last = b'\n';
+
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => {
+                lines += buf[..n].iter().filter(|&&b| b == b'\n').count() as u64;
+                last = buf[n - 1];
+            }
+        }
+    }
+
+    if last != b'\n' {
+        lines += 1;
+    }
+    Ok(lines)
+}
+
+/// Insert a separator between every group of three digits, counting from the right
+///
+/// Padding zeros are grouped like any other digit, so 1000 padded to six digits is 001,000.
+fn group_digits(digits: &str, sep: char) -> String {
+    let mut result = String::with_capacity(digits.len() + digits.len() / 3);
+    for (idx, c) in digits.chars().enumerate() {
+        if idx > 0 && (digits.len() - idx).is_multiple_of(3) {
+            result.push(sep);
+        }
+        result.push(c);
+    }
+    result
+}
+
+fn extension_key(path: &Path) -> String {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+/// A named change to the original name, applied by --transform before it is rendered
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Transform {
+    Lower,
+    Upper,
+    Trim,
+    Strip(String),
+    Prefix(String),
+    Suffix(String),
+    Replace(String, String),
+}
+
+impl Transform {
+    pub fn apply(&self, name: &str) -> String {
+        match self {
+            Transform::Lower => name.to_lowercase(),
+            Transform::Upper => name.to_uppercase(),
+            Transform::Trim => name.trim().into(),
+            Transform::Strip(text) => {
+                let name = name.strip_prefix(text.as_str()).unwrap_or(name);
+                name.strip_suffix(text.as_str()).unwrap_or(name).into()
+            }
+            Transform::Prefix(text) => format!("{}{}", text, name),
+            Transform::Suffix(text) => format!("{}{}", name, text),
+            Transform::Replace(from, to) => name.replace(from.as_str(), to),
+        }
+    }
+}
+
+/// Parse a --transform, e.g. `lower` or `strip:IMG_`
+///
+/// Available transforms are `lower`, `upper`, `trim`, `strip:TEXT` (from either end),
+/// `prefix:TEXT`, `suffix:TEXT` and `replace:FROM=TO`.
+pub fn parse_transform(spec: &str) -> Result<Transform, String> {
+    let (name, argument) = match spec.split_once(':') {
+        Some((name, argument)) => (name, Some(argument)),
+        None => (spec, None),
+    };
+
+    let transform = match (name, argument) {
+        ("lower", None) => Transform::Lower,
+        ("upper", None) => Transform::Upper,
+        ("trim", None) => Transform::Trim,
+        ("strip", Some(text)) => Transform::Strip(text.into()),
+        ("prefix", Some(text)) => Transform::Prefix(text.into()),
+        ("suffix", Some(text)) => Transform::Suffix(text.into()),
+        ("replace", Some(argument)) => {
+            let (from, to) = argument
+                .split_once('=')
+                .ok_or_else(|| format!("expected replace:FROM=TO: {}", spec))?;
+            if from.is_empty() {
+                return Err(format!("nothing to replace: {}", spec));
+            }
+            Transform::Replace(from.into(), to.into())
+        }
+        ("lower" | "upper" | "trim", Some(_)) => {
+            return Err(format!("{} takes no argument: {}", name, spec))
+        }
+        ("strip" | "prefix" | "suffix" | "replace", None) => {
+            return Err(format!("{} needs an argument, e.g. {}:text", name, name))
+        }
+        _ => {
+            return Err(format!(
+            "unknown transform: {} (expected lower, upper, trim, strip, prefix, suffix or replace)",
+            name
+        ))
+        }
+    };
+    Ok(transform)
+}
+
+/// Make a filename web-safe: lowercase, with runs of whitespace and `sep` collapsed to one `sep`
+///
+/// Separators are also trimmed from either end of the name and from around the extension dot.
+fn normalize(name: &str, sep: char) -> String {
+    let mut result = String::with_capacity(name.len());
+    for c in name.chars().flat_map(char::to_lowercase) {
+        let c = if c.is_whitespace() { sep } else { c };
+        if c == sep && (result.is_empty() || result.ends_with(sep) || result.ends_with('.')) {
+            continue;
+        }
+        if c == '.' && result.ends_with(sep) {
+            result.pop();
+        }
+        result.push(c);
+    }
+
+    if result.ends_with(sep) {
+        result.pop();
+    }
+    result
+}
+
+/// Run a --exec command for a file, with `{}` standing for its path, returning what it prints
+fn run_command(command: &str, path: &Path) -> Result<String, RenameError> {
+    let failed = |reason: String| RenameError::Command {
+        path: path.into(),
+        reason,
+    };
+
+    let output = shell(command, path)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| failed(e.to_string()))?;
+    if !output.status.success() {
+        return Err(failed(output.status.to_string()));
+    }
+
+    let stdout = String::from_utf8(output.stdout).map_err(|e| failed(e.to_string()))?;
+    let stem = stdout.trim();
+    if stem.is_empty() {
+        return Err(failed("printed no name".into()));
+    }
+    if stem.chars().any(std::path::is_separator) {
+        return Err(RenameError::Separator {
+            path: path.into(),
+            text: stem.into(),
+        });
+    }
+    Ok(stem.into())
+}
+
+/// A shell running `command` with `{}` standing for `path`
+///
+/// The path is passed to the shell as its first argument rather than pasted into the command, so
+/// that it reaches the command byte for byte, however it is spelled.
+#[cfg(not(windows))]
+fn shell(command: &str, path: &Path) -> Command {
+    let mut shell = Command::new("sh");
+    shell
+        .arg("-c")
+        .arg(command.replace("{}", "\"$1\""))
+        .arg("sh")
+        .arg(path);
+    shell
+}
+
+/// A shell running `command` with `{}` replaced by `path`, quoted as the shell expects
+///
+/// cmd.exe knows only double quotes, which Windows paths cannot contain. The command is passed
+/// as it is, since cmd.exe does not follow the usual rules for unquoting its arguments.
+#[cfg(windows)]
+fn shell(command: &str, path: &Path) -> Command {
+    use std::os::windows::process::CommandExt;
+
+    let quoted = format!("\"{}\"", path.display());
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").raw_arg(command.replace("{}", &quoted));
+    shell
+}
+
+fn is_number(text: &str) -> bool {
+    !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// The number given to the last of `count` files numbered from `start`, passing over skipped numbers
+fn last_index(start: u64, count: usize, skip: &[Range<usize>]) -> u64 {
+    let mut last = (start + count as u64).saturating_sub(1);
+    if skip.is_empty() {
+        return last;
+    }
+
+    let mut idx = start;
+    while idx <= last {
+        if is_skipped(skip, idx) {
+            last += 1;
+        }
+        idx += 1;
+    }
+    last
+}
+
+/// True if `idx` is one of the numbers passed to --skip-numbers
+fn is_skipped(skip: &[Range<usize>], idx: u64) -> bool {
+    skip.iter().any(|range| range.contains(&(idx as usize)))
+}
+
+/// Replace each run of whitespace with a single space
+fn collapse_spaces(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        if !c.is_whitespace() {
+            result.push(c);
+        } else if !result.ends_with(' ') {
+            result.push(' ');
+        }
+    }
+    result
+}
+
+/// Transliterate text to ASCII
+///
+/// Accented characters are decomposed and their combining marks dropped; anything else outside
+/// of ASCII is approximated by deunicode. Path separators are never produced.
+fn to_ascii(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.nfd().filter(|&c| !is_combining_mark(c)) {
+        if c.is_ascii() {
+            result.push(c);
+        } else if let Some(s) = deunicode::deunicode_char(c) {
+            result.push_str(s);
+        }
+    }
+    result.retain(|c| c != '/' && c != '\\');
+    result
+}
+
+fn check_index_range(start: u32, count: usize) -> Result<(), IndexOverflow> {
+    let last = (start as u64 + count as u64).saturating_sub(1);
+    if last > u32::MAX as u64 {
+        return Err(IndexOverflow { start, count });
+    }
+    Ok(())
+}
+
+fn get_width(count: Option<usize>) -> Option<usize> {
+    let count = count?;
+    let mut witness_pairs = iter::successors(Some((1usize, 10usize)), |(width, witness)| {
+        witness.checked_mul(10).map(|witness| (width + 1, witness))
+    });
+
+    witness_pairs.find_map(|witness| {
+        if witness.1 > count {
+            Some(witness.0)
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::args::{Args, OnEmpty, OnMissingTag};
+
+    use super::{RenameError, Renamer};
+
+    fn renamer(template: &str, start: u32, pattern: Option<&str>) -> Renamer {
+        let mut options = Args {
+            template: template.into(),
+            start,
+            pattern: pattern.map(|pattern| regex::Regex::new(pattern).unwrap()),
+            ..Default::default()
+        };
+        Renamer::new(&mut options, None).unwrap()
+    }
+
+    #[test]
+    fn rename_works() {
+        let files = &[
+            "f4240.jpg",
+            "f4241.jpg",
+            "f4242.jpg",
+            "f4243.jpg",
+            "f4244.jpg",
+            "f4245.jpg",
+            "f4246.jpg",
+            "f4247.jpg",
+            "f4248.jpg",
+            "f4249.jpg",
+        ];
+
+        let expected = &[
+            Path::new("Fuzzy Bear 001-f42 (original).jpg"),
+            Path::new("Fuzzy Bear 002-f42 (original).jpg"),
+            Path::new("Fuzzy Bear 003-f42 (original).jpg"),
+            Path::new("Fuzzy Bear 004-f42 (original).jpg"),
+            Path::new("Fuzzy Bear 005-f42 (original).jpg"),
+            Path::new("Fuzzy Bear 006-f42 (original).jpg"),
+            Path::new("Fuzzy Bear 007-f42 (original).jpg"),
+            Path::new("Fuzzy Bear 008-f42 (original).jpg"),
+            Path::new("Fuzzy Bear 009-f42 (original).jpg"),
+            Path::new("Fuzzy Bear 010-f42 (original).jpg"),
+        ];
+
+        let mut renamer = renamer("Fuzzy Bear {n:3}-{o:3} (original)", 1, None);
+
+        let actual = files
+            .iter()
+            .cloned()
+            .map(|x| renamer.rename(x.as_ref()).unwrap());
+
+        for (actual, &expected) in actual.zip(expected) {
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn rename_works_with_offset_idx() {
+        let files = &[
+            "f4240.jpg",
+            "f4241.jpg",
+            "f4242.jpg",
+            "f4243.jpg",
+            "f4244.jpg",
+            "f4245.jpg",
+            "f4246.jpg",
+            "f4247.jpg",
+            "f4248.jpg",
+            "f4249.jpg",
+        ];
+
+        let expected = &[
+            Path::new("Fuzzy Bear 021-f42 (original).jpg"),
+            Path::new("Fuzzy Bear 022-f42 (original).jpg"),
+            Path::new("Fuzzy Bear 023-f42 (original).jpg"),
+            Path::new("Fuzzy Bear 024-f42 (original).jpg"),
+            Path::new("Fuzzy Bear 025-f42 (original).jpg"),
+            Path::new("Fuzzy Bear 026-f42 (original).jpg"),
+            Path::new("Fuzzy Bear 027-f42 (original).jpg"),
+            Path::new("Fuzzy Bear 028-f42 (original).jpg"),
+            Path::new("Fuzzy Bear 029-f42 (original).jpg"),
+            Path::new("Fuzzy Bear 030-f42 (original).jpg"),
+        ];
+
+        let mut renamer = renamer("Fuzzy Bear {n:3}-{o:3} (original)", 21, None);
+
+        let actual = files
+            .iter()
+            .cloned()
+            .map(|x| renamer.rename(x.as_ref()).unwrap());
+
+        for (actual, &expected) in actual.zip(expected) {
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn rename_works_with_filename_pattern() {
+        let files = &[
+            "Highlander S05E01 Prophecy.mp4",
+            "Highlander S04E22 One Minute to Midnight.mp4",
+        ];
+
+        let expected = &[
+            Path::new("S05E01 Prophecy.mp4"),
+            Path::new("S05E02 One Minute to Midnight.mp4"),
+        ];
+
+        let mut renamer = renamer("S05E{0:2} {f}", 1, Some(r#".*S\d\dE\d\d (.+)"#));
+
+        let actual = files
+            .iter()
+            .cloned()
+            .map(|x| renamer.rename(x.as_ref()).unwrap());
+
+        for (actual, &expected) in actual.zip(expected) {
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn word_lengths() {
+        let mut first = renamer("{o:w3}", 1, None);
+        assert_eq!(
+            Path::new("One Two Three.txt"),
+            first.rename("One Two Three Four.txt".as_ref()).unwrap()
+        );
+
+        let mut last = renamer("{o:w-2}", 1, None);
+        assert_eq!(
+            Path::new("Three Four.txt"),
+            last.rename("One Two Three Four.txt".as_ref()).unwrap()
+        );
+
+        let mut more = renamer("{o:w5}", 1, None);
+        assert_eq!(
+            Path::new("One Two.txt"),
+            more.rename("One  Two.txt".as_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn empty_captures() {
+        let path = Path::new("photos/_1.jpg");
+        let with_policy = |on_empty| {
+            let mut options = Args {
+                template: "{o}{n}".into(),
+                start: 1,
+                pattern: Some(regex::Regex::new(r"([a-z]*)_").unwrap()),
+                on_empty,
+                ..Default::default()
+            };
+            Renamer::new(&mut options, None).unwrap()
+        };
+
+        let mut strict = with_policy(OnEmpty::Error);
+        assert!(strict.empty_capture(path));
+        assert!(matches!(
+            strict.rename(path),
+            Err(RenameError::EmptyCapture { .. })
+        ));
+
+        let mut fallback = with_policy(OnEmpty::KeepOriginal);
+        assert_eq!(Path::new("photos/_11.jpg"), fallback.rename(path).unwrap());
+
+        let matched = with_policy(OnEmpty::Skip);
+        assert!(!matched.empty_capture("photos/a_1.jpg".as_ref()));
+    }
+
+    #[test]
+    fn id3_tags() {
+        use id3::TagLike;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("01 track.mp3");
+        std::fs::write(&path, b"").unwrap();
+        let mut tag = id3::Tag::new();
+        tag.set_artist("Low");
+        tag.set_title("Words");
+        tag.write_to_path(&path, id3::Version::Id3v24).unwrap();
+
+        let tagged = |template: &str, on_missing_tag| {
+            let mut options = Args {
+                template: template.into(),
+                on_missing_tag,
+                ..Default::default()
+            };
+            Renamer::new(&mut options, None).unwrap().rename(&path)
+        };
+
+        assert_eq!(
+            dir.path().join("Low - Words.mp3"),
+            tagged("{artist} - {title}", OnMissingTag::Error).unwrap()
+        );
+        assert!(matches!(
+            tagged("{album} - {title}", OnMissingTag::Error),
+            Err(RenameError::MissingTag { .. })
+        ));
+        assert_eq!(
+            dir.path().join(" - Words.mp3"),
+            tagged("{album} - {title}", OnMissingTag::Empty).unwrap()
+        );
+    }
+
+    #[test]
+    fn organize_by_modified_date() {
+        use chrono::{Local, TimeZone};
+
+        let dir = tempfile::tempdir().unwrap();
+        let files = [("a.jpg", 2023, 6), ("b.jpg", 2023, 6), ("c.jpg", 2024, 1)];
+        for (name, year, month) in files {
+            let time = Local.with_ymd_and_hms(year, month, 15, 12, 0, 0).unwrap();
+            std::fs::File::create(dir.path().join(name))
+                .unwrap()
+                .set_modified(time.into())
+                .unwrap();
+        }
+
+        // As given by --organize-by-date %Y-%m
+        let mut options = Args {
+            template: "{m:%Y-%m}/{o}".into(),
+            ..Default::default()
+        };
+        let mut organizer = Renamer::new(&mut options, None).unwrap();
+        let actual: Vec<_> = files
+            .iter()
+            .map(|(name, ..)| organizer.rename(&dir.path().join(name)).unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                dir.path().join("2023-06/a.jpg"),
+                dir.path().join("2023-06/b.jpg"),
+                dir.path().join("2024-01/c.jpg"),
+            ],
+            actual
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exec_names() {
+        let named_by = |command: &str| {
+            let mut options = Args {
+                template: "{o}".into(),
+                exec: Some(command.into()),
+                ..Default::default()
+            };
+            Renamer::new(&mut options, None)
+                .unwrap()
+                .rename("photos/it's.jpg".as_ref())
+        };
+
+        assert_eq!(
+            Path::new("photos/x-it's.jpg"),
+            named_by("echo \"x-$(basename {} .jpg)\"").unwrap()
+        );
+        assert!(matches!(
+            named_by("false"),
+            Err(RenameError::Command { .. })
+        ));
+        assert!(matches!(named_by("true"), Err(RenameError::Command { .. })));
+        assert!(matches!(
+            named_by("echo a/b"),
+            Err(RenameError::Separator { .. })
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exec_passes_non_utf8_paths_intact() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(OsStr::from_bytes(b"caf\xe9.jpg"));
+        std::fs::write(&path, b"").unwrap();
+
+        let mut options = Args {
+            template: "{o}".into(),
+            exec: Some("test -f {} && echo found".into()),
+            lossy_names: true,
+            ..Default::default()
+        };
+        let renamed = Renamer::new(&mut options, None).unwrap().rename(&path);
+        assert_eq!(dir.path().join("found.jpg"), renamed.unwrap());
+    }
+
+    #[test]
+    fn raw_and_extracted_names() {
+        let mut with_pattern = renamer("{o:3}_{O:3}_{O}", 1, Some(r"IMG_(\d+)"));
+        assert_eq!(
+            Path::new("123_IMG_IMG_12345.jpg"),
+            with_pattern.rename("IMG_12345.jpg".as_ref()).unwrap()
+        );
+
+        let mut without_pattern = renamer("{f}-{F:2}", 1, None);
+        assert_eq!(
+            Path::new("ab-ab.txt"),
+            without_pattern.rename("ab.txt".as_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn pattern_can_match_full_path() {
+        let mut options = Args {
+            template: "{o} {n:2}".into(),
+            start: 1,
+            pattern: regex::Regex::new(r"shows[/\\]([^/\\]+)[/\\]").ok(),
+            match_path: true,
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+
+        let path = Path::new("shows").join("Highlander").join("S01E01.mkv");
+        let expected = Path::new("shows")
+            .join("Highlander")
+            .join("Highlander 01.mkv");
+        assert_eq!(expected, renamer.rename(&path).unwrap());
+
+        let path = Path::new("movies").join("Highlander.mkv");
+        let expected = Path::new("movies").join("Highlander 02.mkv");
+        assert_eq!(expected, renamer.rename(&path).unwrap());
+    }
+
+    #[test]
+    fn rename_into_out_dir() {
+        let mut options = Args {
+            template: "{o}".into(),
+            start: 1,
+            out_dir: Some("flat".into()),
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+
+        let path = Path::new("a").join("b").join("photo.jpg");
+        assert_eq!(
+            Path::new("flat").join("photo.jpg"),
+            renamer.rename(&path).unwrap()
+        );
+    }
+
+    #[test]
+    fn replace_matches_in_stem() {
+        let mut options = Args {
+            template: "{o}".into(),
+            start: 1,
+            pattern: regex::Regex::new(" ").ok(),
+            replacement: Some("_".into()),
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+
+        let files = ["My Holiday 01.jpg", "My Holiday 02.jpg", "notes.txt"];
+        let actual: Vec<_> = files
+            .iter()
+            .map(|x| renamer.rename(x.as_ref()).unwrap())
+            .collect();
+        assert_eq!(
+            actual,
+            [
+                Path::new("My_Holiday_01.jpg"),
+                Path::new("My_Holiday_02.jpg"),
+                Path::new("notes.txt"),
+            ]
+        );
+
+        let mut options = Args {
+            template: "{o}".into(),
+            start: 1,
+            pattern: regex::Regex::new(r"(\w+) - (\w+)").ok(),
+            replacement: Some("$2 - $1".into()),
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+        assert_eq!(
+            Path::new("Title - Artist.mp3"),
+            renamer.rename("Artist - Title.mp3".as_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn replace_in_extension() {
+        let mut options = Args {
+            template: "{o}".into(),
+            ext_pattern: regex::Regex::new("(?i)^jpe?g$").ok(),
+            ext_replace: Some("jpg".into()),
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+
+        let files = ["a.jpeg", "b.jpg", "c.JPG", "d.png", "e"];
+        let actual: Vec<_> = files
+            .iter()
+            .map(|x| renamer.rename(x.as_ref()).unwrap())
+            .collect();
+        assert_eq!(
+            actual,
+            [
+                Path::new("a.jpg"),
+                Path::new("b.jpg"),
+                Path::new("c.jpg"),
+                Path::new("d.png"),
+                Path::new("e"),
+            ]
+        );
+    }
+
+    #[test]
+    fn detect_extension_from_content() {
+        const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("download.bin");
+        let unknown = dir.path().join("notes.bin");
+        std::fs::write(&image, PNG).unwrap();
+        std::fs::write(&unknown, b"just some text").unwrap();
+
+        let mut options = Args {
+            template: "{o}".into(),
+            detect_extension: true,
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+        assert_eq!(
+            dir.path().join("download.png"),
+            renamer.rename(&image).unwrap()
+        );
+        assert_eq!(unknown, renamer.rename(&unknown).unwrap());
+    }
+
+    #[test]
+    fn normalize_filename() {
+        assert_eq!("my-photo-01.jpg", super::normalize("My Photo 01.JPG", '-'));
+        assert_eq!("a-b.txt", super::normalize(" A  -  b .txt", '-'));
+        assert_eq!("report_final", super::normalize("Report\tFinal", '_'));
+
+        let mut options = Args {
+            template: "{o}".into(),
+            normalize: Some('-'),
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+        assert_eq!(
+            Path::new("Photos/my-photo-01.jpg"),
+            renamer.rename("Photos/My Photo 01.JPG".as_ref()).unwrap()
+        );
+
+        options.normalize = Some('/');
+        assert!(Renamer::new(&mut options, None).is_err());
+    }
+
+    #[test]
+    fn skip_numbers() {
+        let mut options = Args {
+            template: "{n}".into(),
+            start: 1,
+            skip_numbers: vec![13..14, 30..40],
+            ..Default::default()
+        };
+        let mut skipping = Renamer::new(&mut options, Some(20)).unwrap();
+        let names: Vec<_> = (1..=20)
+            .map(|idx| skipping.rename(format!("{idx}.txt").as_ref()).unwrap())
+            .collect();
+
+        assert_eq!(Path::new("12.txt"), names[11]);
+        assert_eq!(Path::new("14.txt"), names[12]);
+        assert_eq!(Path::new("21.txt"), names[19]);
+
+        // The last number used sets the width
+        let mut options = Args {
+            template: "{n}".into(),
+            start: 1,
+            skip_numbers: vec![2..3, 5..10],
+            ..Default::default()
+        };
+        let mut skipping = Renamer::new(&mut options, Some(9)).unwrap();
+        assert_eq!(
+            Path::new("01.txt"),
+            skipping.rename("a.txt".as_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn sidecar_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<_> = ["a.jpg", "b.jpg", "c.jpg"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        for path in &paths {
+            std::fs::write(path, "").unwrap();
+        }
+        std::fs::write(dir.path().join("b.jpg.name"), "Sunset over Moab\n").unwrap();
+
+        let mut options = Args {
+            template: "photo-{n}".into(),
+            start: 1,
+            sidecar: Some("name".into()),
+            ..Default::default()
+        };
+        let mut curated = Renamer::new(&mut options, Some(3)).unwrap();
+        let names: Vec<_> = paths
+            .iter()
+            .map(|path| curated.rename(path).unwrap())
+            .collect();
+
+        assert_eq!(
+            vec![
+                dir.path().join("photo-1.jpg"),
+                dir.path().join("Sunset over Moab.jpg"),
+                dir.path().join("photo-2.jpg"),
+            ],
+            names
+        );
+    }
+
+    #[test]
+    fn skip_numbers_near_the_end_set_width() {
+        let names = |skip_numbers| {
+            let mut options = Args {
+                template: "{n}".into(),
+                start: 1,
+                skip_numbers,
+                ..Default::default()
+            };
+            let mut skipping = Renamer::new(&mut options, Some(9)).unwrap();
+            (1..=9)
+                .map(|idx| skipping.rename(format!("{idx}.txt").as_ref()).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // 1 through 7, then 10 and 11
+        let widened = names(vec![8..10, 20..30]);
+        assert_eq!(Path::new("01.txt"), widened[0]);
+        assert_eq!(Path::new("07.txt"), widened[6]);
+        assert_eq!(Path::new("11.txt"), widened[8]);
+
+        // Skipped numbers past the end of the sequence change nothing
+        let unchanged = names(vec![10..20, 30..40]);
+        assert_eq!(Path::new("1.txt"), unchanged[0]);
+        assert_eq!(Path::new("9.txt"), unchanged[8]);
+    }
+
+    #[test]
+    fn collapse_spaces() {
+        assert_eq!("foo bar", super::collapse_spaces("foo    bar"));
+        assert_eq!(" a b ", super::collapse_spaces("\t a \n b  "));
+
+        let mut options = Args {
+            template: "{o}".into(),
+            collapse_spaces: true,
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+        assert_eq!(
+            Path::new("Photos/foo bar.  jpg"),
+            renamer.rename("Photos/foo    bar.  jpg".as_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn prefix_and_suffix() {
+        let rename = |prefix: Option<&str>, suffix: Option<&str>| {
+            let mut options = Args {
+                template: "{o}".into(),
+                start: 1,
+                prefix: prefix.map(String::from),
+                suffix: suffix.map(String::from),
+                ..Default::default()
+            };
+            let mut renamer = Renamer::new(&mut options, None).unwrap();
+            renamer.rename("photos/beach.jpg".as_ref()).unwrap()
+        };
+
+        assert_eq!(
+            Path::new("photos/2023_beach.jpg"),
+            rename(Some("2023_"), None)
+        );
+        assert_eq!(
+            Path::new("photos/beach (1).jpg"),
+            rename(None, Some(" (1)"))
+        );
+        assert_eq!(
+            Path::new("photos/{n}beach{o}.jpg"),
+            rename(Some("{n}"), Some("{o}"))
+        );
+    }
+
+    #[test]
+    fn strip_prefix_and_suffix() {
+        let mut options = Args {
+            template: "{o}".into(),
+            start: 1,
+            strip_prefix: Some("IMG_".into()),
+            strip_suffix: Some("_final".into()),
+            prefix: Some("2023_".into()),
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+
+        assert_eq!(
+            Path::new("photos/2023_0042.jpg"),
+            renamer
+                .rename("photos/IMG_0042_final.jpg".as_ref())
+                .unwrap()
+        );
+        assert_eq!(
+            Path::new("photos/2023_my_IMG_final_cut.jpg"),
+            renamer
+                .rename("photos/my_IMG_final_cut.jpg".as_ref())
+                .unwrap()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_unicode_names_are_refused() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let path = Path::new(OsStr::from_bytes(b"caf\xe9.txt"));
+
+        let mut strict = renamer("{o} {n}", 1, None);
+        assert!(strict.rename(path).is_err());
+
+        let mut unnamed = renamer("photo {n}", 1, None);
+        assert_eq!(Path::new("photo 1.txt"), unnamed.rename(path).unwrap());
+
+        let mut options = Args {
+            template: "{o} {n}".into(),
+            start: 1,
+            lossy_names: true,
+            ..Default::default()
+        };
+        let mut lossy = Renamer::new(&mut options, None).unwrap();
+        assert_eq!(Path::new("caf\u{FFFD} 1.txt"), lossy.rename(path).unwrap());
+    }
+
+    #[test]
+    fn ascii_names() {
+        assert_eq!("cafe", super::to_ascii("café"));
+        assert_eq!("Angstrom", super::to_ascii("Ångström"));
+        assert_eq!("naive facade", super::to_ascii("naïve façade"));
+        assert_eq!("Zolw", super::to_ascii("Żółw"));
+        assert_eq!("Bei Jing ", super::to_ascii("北京"));
+        assert!(!super::to_ascii("1⁄2").contains('/'));
+
+        let mut options = Args {
+            template: "{o:4}".into(),
+            start: 1,
+            ascii: true,
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+        assert_eq!(
+            Path::new("Emil.txt"),
+            renamer.rename("Émile Zola.txt".as_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn counter_per_extension() {
+        let files: Vec<_> = (1..=12)
+            .map(|i| {
+                let ext = if i % 2 == 0 { "png" } else { "jpg" };
+                format!("{:02}.{}", i, ext)
+            })
+            .chain(Some(String::from("13.JPG")))
+            .collect();
+
+        let mut options = Args {
+            template: "img-{n}".into(),
+            start: 1,
+            counter_per_ext: true,
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, Some(files.len())).unwrap();
+        renamer.scan(&files).unwrap();
+
+        let actual: Vec<_> = files
+            .iter()
+            .map(|x| renamer.rename(x.as_ref()).unwrap())
+            .collect();
+
+        // Seven jpgs (counting the uppercase extension) and six pngs, so neither needs two digits
+        let expected = [
+            "img-1.jpg",
+            "img-1.png",
+            "img-2.jpg",
+            "img-2.png",
+            "img-3.jpg",
+            "img-3.png",
+            "img-4.jpg",
+            "img-4.png",
+            "img-5.jpg",
+            "img-5.png",
+            "img-6.jpg",
+            "img-6.png",
+            "img-7.JPG",
+        ];
+        assert_eq!(
+            actual,
+            expected.iter().map(PathBuf::from).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn continue_numbering() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["002.jpg", "005.jpg", "cover.jpg"] {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+
+        let files = vec![dir.path().join("a.jpg"), dir.path().join("b.jpg")];
+        let mut options = Args {
+            template: "{n:3}".into(),
+            start: 1,
+            continue_numbering: true,
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, Some(files.len())).unwrap();
+        renamer.scan(&files).unwrap();
+
+        let actual: Vec<_> = files.iter().map(|x| renamer.rename(x).unwrap()).collect();
+        assert_eq!(
+            actual,
+            [dir.path().join("006.jpg"), dir.path().join("007.jpg")]
+        );
+    }
+
+    #[test]
+    fn modulo_wraps_index() {
+        let mut options = Args {
+            template: "tile_{n}".into(),
+            start: 0,
+            modulo: Some(4),
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, Some(8)).unwrap();
+        let actual: Vec<_> = (0..8)
+            .map(|i| renamer.rename(format!("{}.png", i).as_ref()).unwrap())
+            .collect();
+        let expected: Vec<_> = [0, 1, 2, 3, 0, 1, 2, 3]
+            .iter()
+            .map(|i| PathBuf::from(format!("tile_{}.png", i)))
+            .collect();
+        assert_eq!(expected, actual);
+
+        let mut options = Args {
+            template: "tile_{n}".into(),
+            start: 1,
+            modulo: Some(12),
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, Some(3)).unwrap();
+        assert_eq!(
+            Path::new("tile_01.png"),
+            renamer.rename("a.png".as_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn match_index_selects_occurrence() {
+        let mut options = Args {
+            template: "{o}".into(),
+            pattern: regex::Regex::new(r"\d+").ok(),
+            match_index: 1,
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+        assert_eq!(
+            Path::new("42.jpg"),
+            renamer.rename("scan 7 page 42.jpg".as_ref()).unwrap()
+        );
+
+        let error = renamer.rename("scan 7.jpg".as_ref()).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("match index 1 is out of range: pattern matches 1 times"));
+    }
+
+    #[test]
+    fn named_groups() {
+        let mut renamer = renamer(
+            "{g:show} {g:season}x{g:episode} - {g:4}",
+            1,
+            Some(r"(?P<show>.+) S(?P<season>\d+)E(?P<episode>\d+) (.+)"),
+        );
+        assert_eq!(
+            Path::new("Highlander 05x01 - Prophecy.mp4"),
+            renamer
+                .rename("Highlander S05E01 Prophecy.mp4".as_ref())
+                .unwrap()
+        );
+        assert!(renamer.rename("Trailer.mp4".as_ref()).is_err());
+
+        let mut options = Args {
+            template: "{g:title}".into(),
+            pattern: regex::Regex::new(r"(?P<show>.+)").ok(),
+            ..Default::default()
+        };
+        assert!(Renamer::new(&mut options, None).is_err());
+    }
+
+    #[test]
+    fn format_from_captures() {
+        let mut options = Args {
+            format: Some("{3}-{2}-{1} {n}".into()),
+            start: 1,
+            pattern: regex::Regex::new(r"(\d{2})\.(\d{2})\.(\d{4})").ok(),
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+        assert_eq!(
+            Path::new("2023-06-01 1.jpg"),
+            renamer.rename("01.06.2023.jpg".as_ref()).unwrap()
+        );
+        assert_eq!(
+            Path::new("2024-12-31 2.jpg"),
+            renamer.rename("scan 31.12.2024.jpg".as_ref()).unwrap()
+        );
+
+        let mut options = Args {
+            format: Some("{4}".into()),
+            pattern: regex::Regex::new(r"(\d{2})\.(\d{2})\.(\d{4})").ok(),
+            ..Default::default()
+        };
+        assert!(Renamer::new(&mut options, None).is_err());
+    }
+
+    #[test]
+    fn strip_match() {
+        let mut options = Args {
+            template: "{o}".into(),
+            pattern: regex::Regex::new(r"\(copy\)").ok(),
+            strip_match: true,
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+
+        let files = [
+            "file (1) (copy).txt",
+            "report (copy) final.doc",
+            "clean.txt",
+        ];
+        let actual: Vec<_> = files
+            .iter()
+            .map(|x| renamer.rename(x.as_ref()).unwrap())
+            .collect();
+        assert_eq!(
+            actual,
+            [
+                Path::new("file (1).txt"),
+                Path::new("report final.doc"),
+                Path::new("clean.txt"),
+            ]
+        );
+    }
+
+    #[test]
+    fn depth_below_directory_arguments() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        for name in ["top.txt", "a/mid.txt", "a/b/deep.txt"] {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+
+        let mut options = Args {
+            template: "{o}-d{depth}".into(),
+            paths: vec![dir.path().to_string_lossy().into_owned()],
+            ..Default::default()
+        };
+        let mut walked = Renamer::new(&mut options, None).unwrap();
+        for (name, expected) in [
+            ("top.txt", "top-d1.txt"),
+            ("a/mid.txt", "mid-d2.txt"),
+            ("a/b/deep.txt", "deep-d3.txt"),
+        ] {
+            let path = dir.path().join(name);
+            assert_eq!(path.with_file_name(expected), walked.rename(&path).unwrap());
+        }
+
+        let mut direct = renamer("{o}-d{depth}", 1, None);
+        let path = dir.path().join("a/mid.txt");
+        assert_eq!(
+            path.with_file_name("mid-d0.txt"),
+            direct.rename(&path).unwrap()
+        );
+    }
+
+    #[test]
+    fn template_separators_create_directories() {
+        let mut renamer = renamer("{g:1}/{g:2}", 1, Some(r"(\d{4})_(.+)"));
+        assert_eq!(
+            Path::new("photos/2021/foo.jpg"),
+            renamer.rename("photos/2021_foo.jpg".as_ref()).unwrap()
+        );
+        assert_eq!(
+            Path::new("photos/2022/bar.jpg"),
+            renamer.rename("photos/2022_bar.jpg".as_ref()).unwrap()
+        );
+
+        let mut options = Args {
+            template: "{o}".into(),
+            pattern: Some(regex::Regex::new(r"(\w+/\w+)\.txt$").unwrap()),
+            match_path: true,
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+        assert!(matches!(
+            renamer.rename("docs/a/b.txt".as_ref()),
+            Err(super::RenameError::Separator { .. })
+        ));
+    }
+
+    #[test]
+    fn reset_on_change() {
+        let mut options = Args {
+            template: "{n:2} {g:title}".into(),
+            start: 1,
+            pattern: Some(regex::Regex::new(r"^(?P<album>.+) - (?P<title>.+)$").unwrap()),
+            reset_on_change: Some("album".into()),
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+
+        let files = [
+            ("Abbey Road - Come Together.mp3", "01 Come Together.mp3"),
+            ("Abbey Road - Something.mp3", "02 Something.mp3"),
+            ("Revolver - Taxman.mp3", "01 Taxman.mp3"),
+            ("Revolver - Eleanor Rigby.mp3", "02 Eleanor Rigby.mp3"),
+            (
+                "Revolver - I'm Only Sleeping.mp3",
+                "03 I'm Only Sleeping.mp3",
+            ),
+        ];
+        for (from, to) in files {
+            assert_eq!(Path::new(to), renamer.rename(from.as_ref()).unwrap());
+        }
+
+        let mut options = Args {
+            template: "{n}".into(),
+            pattern: Some(regex::Regex::new(r"(.+) - ").unwrap()),
+            reset_on_change: Some("album".into()),
+            ..Default::default()
+        };
+        assert!(Renamer::new(&mut options, None).is_err());
+    }
+
+    #[test]
+    fn width_follows_the_last_number() {
+        let rename_all = |start| {
+            let mut options = Args {
+                template: "{n}".into(),
+                start,
+                ..Default::default()
+            };
+            let mut renamer = Renamer::new(&mut options, Some(10)).unwrap();
+            (0..10)
+                .map(|idx| renamer.rename(format!("{idx}.txt").as_ref()).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let names = rename_all(95);
+        assert_eq!(Path::new("095.txt"), names[0]);
+        assert_eq!(Path::new("104.txt"), names[9]);
+
+        let names = rename_all(0);
+        assert_eq!(Path::new("0.txt"), names[0]);
+        assert_eq!(Path::new("9.txt"), names[9]);
+    }
+
+    #[test]
+    fn parent_path_flattens_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        std::fs::write(dir.path().join("a/b/c.jpg"), b"").unwrap();
+        std::fs::write(dir.path().join("top.jpg"), b"").unwrap();
+
+        let mut options = Args {
+            template: "{pp}{o}".into(),
+            paths: vec![dir.path().to_string_lossy().into_owned()],
+            out_dir: Some(dir.path().to_owned()),
+            path_sep_replacement: Some('_'),
+            ..Default::default()
+        };
+        let mut flattener = Renamer::new(&mut options, None).unwrap();
+        assert_eq!(
+            dir.path().join("a_bc.jpg"),
+            flattener.rename(&dir.path().join("a/b/c.jpg")).unwrap()
+        );
+        assert_eq!(
+            dir.path().join("top.jpg"),
+            flattener.rename(&dir.path().join("top.jpg")).unwrap()
+        );
+
+        options.template = "{pp}_{o}".into();
+        options.path_sep_replacement = Some('-');
+        let mut flattener = Renamer::new(&mut options, None).unwrap();
+        assert_eq!(
+            dir.path().join("a-b_c.jpg"),
+            flattener.rename(&dir.path().join("a/b/c.jpg")).unwrap()
+        );
+    }
+
+    #[test]
+    fn pattern_ignore_case() {
+        let mut options = Args {
+            template: "{g:show} {g:season}x{g:episode}".into(),
+            pattern: Some(
+                regex::Regex::new(r"(?P<show>.+)\.s(?P<season>\d+)e(?P<episode>\d+)").unwrap(),
+            ),
+            pattern_ignore_case: true,
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+
+        for (from, to) in [
+            ("Highlander.S01E02.mkv", "Highlander 01x02.mkv"),
+            ("Highlander.s01e03.mkv", "Highlander 01x03.mkv"),
+            ("Highlander.S01e04.mkv", "Highlander 01x04.mkv"),
+        ] {
+            assert_eq!(Path::new(to), renamer.rename(from.as_ref()).unwrap());
+        }
+    }
+
+    #[test]
+    fn empty_template_is_an_error() {
+        for template in ["", "  "] {
+            let mut renamer = renamer(template, 1, None);
+            let error = renamer.rename("photo.jpg".as_ref()).unwrap_err();
+            assert!(matches!(error, super::RenameError::EmptyName { .. }));
+            assert!(error.to_string().contains("include {o} or {n}"));
         }
     }
 
+    #[test]
+    fn transforms_apply_in_order() {
+        let transform = |specs: &[&str]| {
+            let mut options = Args {
+                template: "{o}".into(),
+                transform: specs
+                    .iter()
+                    .map(|spec| super::parse_transform(spec).unwrap())
+                    .collect(),
+                ..Default::default()
+            };
+            let mut renamer = Renamer::new(&mut options, None).unwrap();
+            renamer.rename("photos/IMG_Beach.JPG".as_ref()).unwrap()
+        };
+
+        assert_eq!(
+            Path::new("photos/foo_beach.JPG"),
+            transform(&["strip:IMG_", "lower", "prefix:foo_"])
+        );
+        assert_eq!(
+            Path::new("photos/foo_img_beach.JPG"),
+            transform(&["prefix:foo_", "lower", "strip:IMG_"])
+        );
+        assert_eq!(
+            Path::new("photos/IMG-Sea.JPG"),
+            transform(&["replace:_=-", "replace:Beach=Sea"])
+        );
+
+        assert!(super::parse_transform("shout").is_err());
+        assert!(super::parse_transform("lower:x").is_err());
+        assert!(super::parse_transform("prefix").is_err());
+        assert!(super::parse_transform("replace:x").is_err());
+    }
+
+    #[test]
+    fn line_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data.csv");
+        let partial = dir.path().join("partial.csv");
+        std::fs::write(&data, "a,b\n1,2\n3,4\n").unwrap();
+        std::fs::write(&partial, "a,b\n1,2").unwrap();
+
+        let mut renamer = renamer("data-{lines}", 1, None);
+        assert_eq!(
+            dir.path().join("data-3.csv"),
+            renamer.rename(&data).unwrap()
+        );
+        assert_eq!(
+            dir.path().join("data-2.csv"),
+            renamer.rename(&partial).unwrap()
+        );
+        assert!(renamer.rename(&dir.path().join("missing.csv")).is_err());
+    }
+
+    #[test]
+    fn grouped_numbers() {
+        assert_eq!("1,000", super::group_digits("1000", ','));
+        assert_eq!("1_000_000", super::group_digits("1000000", '_'));
+        assert_eq!("100", super::group_digits("100", ','));
+        assert_eq!("001,000", super::group_digits("001000", ','));
+
+        let mut options = Args {
+            template: "report {n:group}".into(),
+            start: 1000,
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+        assert_eq!(
+            Path::new("report 1,000.txt"),
+            renamer.rename("a.txt".as_ref()).unwrap()
+        );
+
+        let mut options = Args {
+            template: "report {n:8:group}".into(),
+            start: 1000000,
+            group_sep: Some('_'),
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+        assert_eq!(
+            Path::new("report 01_000_000.txt"),
+            renamer.rename("a.txt".as_ref()).unwrap()
+        );
+
+        let mut options = Args {
+            template: "{n:group}".into(),
+            group_sep: Some('/'),
+            ..Default::default()
+        };
+        assert!(Renamer::new(&mut options, None).is_err());
+    }
+
+    #[test]
+    fn number_only_colliding_targets() {
+        let mut targets: Vec<_> = ["a.txt", "report.txt", "b.txt", "report.txt", "c.txt"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        super::number_collisions(&mut targets);
+
+        let expected: Vec<_> = [
+            "a.txt",
+            "report (1).txt",
+            "b.txt",
+            "report (2).txt",
+            "c.txt",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(expected, targets);
+
+        let mut targets: Vec<_> = ["x.txt", "x (1).txt", "x.txt"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        super::number_collisions(&mut targets);
+        assert_eq!(
+            targets,
+            [
+                Path::new("x (2).txt"),
+                Path::new("x (1).txt"),
+                Path::new("x (3).txt")
+            ]
+        );
+    }
+
+    #[test]
+    fn min_width() {
+        let files = ["a.txt", "b.txt"];
+        let mut options = Args {
+            template: "{n}-{z:2}".into(),
+            start: 1,
+            min_width: 4,
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, Some(files.len())).unwrap();
+        let actual: Vec<_> = files
+            .iter()
+            .map(|x| renamer.rename(x.as_ref()).unwrap())
+            .collect();
+        assert_eq!(
+            actual,
+            [Path::new("0001-0000.txt"), Path::new("0002-0001.txt")]
+        );
+
+        let mut options = Args {
+            template: "{n:6}".into(),
+            start: 1,
+            min_width: 4,
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, Some(2)).unwrap();
+        assert_eq!(
+            Path::new("000001.txt"),
+            renamer.rename("a.txt".as_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn custom_pad_char() {
+        let mut options = Args {
+            template: "{n:3}".into(),
+            start: 9,
+            pad_char: Some(' '),
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+        assert_eq!(
+            Path::new("  9.txt"),
+            renamer.rename("a.txt".as_ref()).unwrap()
+        );
+        assert_eq!(
+            Path::new(" 10.txt"),
+            renamer.rename("b.txt".as_ref()).unwrap()
+        );
+
+        let mut options = Args {
+            template: "{n:7:group}".into(),
+            start: 1000,
+            pad_char: Some('_'),
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+        assert_eq!(
+            Path::new("__1,000.txt"),
+            renamer.rename("a.txt".as_ref()).unwrap()
+        );
+
+        let mut options = Args {
+            template: "{n:3}".into(),
+            pad_char: Some('/'),
+            ..Default::default()
+        };
+        assert!(Renamer::new(&mut options, None).is_err());
+    }
+
+    #[test]
+    fn width_from_capture() {
+        let files = ["p7.jpg", "p42.jpg", "p120.jpg", "cover.jpg"];
+        let mut options = Args {
+            template: "{n}".into(),
+            start: 1,
+            pattern: regex::Regex::new(r"p(\d+)").ok(),
+            width_from_capture: true,
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, Some(files.len())).unwrap();
+        renamer.scan(&files).unwrap();
+
+        let actual: Vec<_> = files
+            .iter()
+            .map(|x| renamer.rename(x.as_ref()).unwrap())
+            .collect();
+        assert_eq!(
+            actual,
+            [
+                Path::new("001.jpg"),
+                Path::new("002.jpg"),
+                Path::new("003.jpg"),
+                Path::new("004.jpg"),
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_pad_original() {
+        let files = ["a1.txt", "a22.txt", "a333.txt", "b.txt"];
+        let mut options = Args {
+            template: "a{o}".into(),
+            pattern: regex::Regex::new(r"a(\d+)").ok(),
+            zero_pad_original: true,
+            ..Default::default()
+        };
+        let mut padding = Renamer::new(&mut options, Some(files.len())).unwrap();
+        padding.scan(&files).unwrap();
+
+        let actual: Vec<_> = files
+            .iter()
+            .map(|x| padding.rename(x.as_ref()).unwrap())
+            .collect();
+        assert_eq!(
+            actual,
+            [
+                Path::new("a001.txt"),
+                Path::new("a022.txt"),
+                Path::new("a333.txt"),
+                Path::new("ab.txt"),
+            ]
+        );
+    }
+
+    #[test]
+    fn pad_captured_number() {
+        let mut renamer = renamer("p{cn:3}", 1, Some(r"p(\d+)"));
+        assert_eq!(
+            Path::new("p007.jpg"),
+            renamer.rename("p7.jpg".as_ref()).unwrap()
+        );
+        assert_eq!(
+            Path::new("p120.jpg"),
+            renamer.rename("p120.jpg".as_ref()).unwrap()
+        );
+        assert_eq!(
+            Path::new("p1234.jpg"),
+            renamer.rename("p1234.jpg".as_ref()).unwrap()
+        );
+        assert!(renamer.rename("cover.jpg".as_ref()).is_err());
+    }
+
+    #[test]
+    fn zero_based_index() {
+        let files = ["a.txt", "b.txt", "c.txt"];
+        let mut options = Args {
+            template: "{n:3}-{z:3}".into(),
+            start: 1,
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, Some(files.len())).unwrap();
+        let actual: Vec<_> = files
+            .iter()
+            .map(|x| renamer.rename(x.as_ref()).unwrap())
+            .collect();
+
+        assert_eq!(
+            actual,
+            [
+                Path::new("001-000.txt"),
+                Path::new("002-001.txt"),
+                Path::new("003-002.txt"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rename_without_extension() {
+        let mut options = Args {
+            template: "note-{n}".into(),
+            start: 1,
+            no_extension: true,
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+
+        assert_eq!(
+            Path::new("dir/note-1"),
+            renamer.rename("dir/a.txt".as_ref()).unwrap()
+        );
+        assert_eq!(
+            Path::new("dir/note-2"),
+            renamer.rename("dir/b.txt".as_ref()).unwrap()
+        );
+
+        let mut options = Args {
+            template: "{o}.md".into(),
+            start: 1,
+            no_extension: true,
+            ..Default::default()
+        };
+        let mut renamer = Renamer::new(&mut options, None).unwrap();
+        assert_eq!(
+            Path::new("readme.md"),
+            renamer.rename("readme.txt".as_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn now_is_shared_across_batch() {
+        let mut renamer = renamer("backup-{now:%Y%m%d%H%M%S%f}-{n:3}", 1, None);
+        let stamps: Vec<_> = (0..5)
+            .map(|_| {
+                let path = renamer.rename("a.txt".as_ref()).unwrap();
+                let name = path.to_string_lossy().into_owned();
+                name[..name.rfind('-').unwrap()].to_owned()
+            })
+            .collect();
+
+        assert!(stamps.iter().all(|stamp| stamp == &stamps[0]));
+    }
+
+    #[test]
+    fn timestamps_are_unique() {
+        use std::collections::HashSet;
+
+        let mut renamer = renamer("{o}-{ts}", 1, None);
+        let names: HashSet<_> = (0..1000)
+            .map(|_| renamer.rename("app.log".as_ref()).unwrap())
+            .collect();
+        assert_eq!(1000, names.len());
+    }
+
+    #[test]
+    fn index_range_is_checked() {
+        assert!(super::check_index_range(u32::MAX - 1, 2).is_ok());
+        assert!(super::check_index_range(u32::MAX - 1, 3).is_err());
+        assert!(super::check_index_range(u32::MAX, 0).is_ok());
+
+        let mut options = Args {
+            template: "{n}".into(),
+            start: u32::MAX - 1,
+            ..Default::default()
+        };
+        assert!(Renamer::new(&mut options, Some(3)).is_err());
+
+        let mut renamer = Renamer::new(&mut options, Some(2)).unwrap();
+        assert_eq!(
+            Path::new(&(u32::MAX - 1).to_string()),
+            renamer.rename("a".as_ref()).unwrap()
+        );
+        assert_eq!(
+            Path::new(&u32::MAX.to_string()),
+            renamer.rename("b".as_ref()).unwrap()
+        );
+    }
+
     #[test]
     fn get_width() {
         assert_eq!(Some(1), super::get_width(Some(1)));
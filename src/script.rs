@@ -0,0 +1,121 @@
+use std::{
+    collections::BTreeSet,
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::iter::Operation;
+
+/// Write a path quoted for a POSIX shell
+///
+/// The path is wrapped in single quotes, within which only the single quote itself is special;
+/// each is written as `'\''`. On Unix the path is written byte for byte, whatever its encoding;
+/// elsewhere, a path which is not valid Unicode cannot be written and is an error.
+fn write_quoted(writer: &mut impl Write, path: &Path) -> io::Result<()> {
+    writer.write_all(b"'")?;
+    for (idx, part) in path_bytes(path)?.split(|&b| b == b'\'').enumerate() {
+        if idx > 0 {
+            writer.write_all(br"'\''")?;
+        }
+        writer.write_all(part)?;
+    }
+    writer.write_all(b"'")
+}
+
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> io::Result<&[u8]> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Ok(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> io::Result<&[u8]> {
+    path.to_str().map(str::as_bytes).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "cannot write a path which is not valid Unicode: {}",
+                path.display()
+            ),
+        )
+    })
+}
+
+/// Write a shell script performing `operations` in order with `command`, e.g. `mv` or `cp`
+///
+/// Operations whose source and target are the same are omitted. With `create_dirs`, the script
+/// first creates the directory of every target.
+pub fn write<'a>(
+    writer: &mut impl Write,
+    command: &str,
+    create_dirs: bool,
+    operations: impl IntoIterator<Item = Operation<'a>>,
+) -> io::Result<()> {
+    let operations: Vec<_> = operations
+        .into_iter()
+        .filter(|op| op.from != op.to)
+        .collect();
+
+    writeln!(writer, "#!/bin/sh")?;
+    writeln!(writer, "set -e")?;
+
+    if create_dirs {
+        let dirs: BTreeSet<_> = operations
+            .iter()
+            .filter_map(|op| op.to.parent())
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .collect();
+        for dir in dirs {
+            write!(writer, "mkdir -p -- ")?;
+            write_quoted(writer, dir)?;
+            writeln!(writer)?;
+        }
+    }
+
+    for op in operations {
+        write!(writer, "{} -- ", command)?;
+        write_quoted(writer, op.from)?;
+        write!(writer, " ")?;
+        write_quoted(writer, op.to)?;
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::iter::Forward;
+
+    #[test]
+    fn quote_handles_quotes_and_spaces() {
+        let mut output = Vec::new();
+        super::write_quoted(&mut output, Path::new("dir/it's a $file.txt")).unwrap();
+        assert_eq!(br"'dir/it'\''s a $file.txt'", &*output);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn quote_keeps_non_utf8_bytes() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let mut output = Vec::new();
+        let path = Path::new(OsStr::from_bytes(b"caf\xe9's.jpg"));
+        super::write_quoted(&mut output, path).unwrap();
+        assert_eq!(b"'caf\xe9'\\''s.jpg'", &*output);
+    }
+
+    #[test]
+    fn write_script() {
+        let from = ["a b.txt", "same.txt", "c'd.txt"];
+        let to = ["out/1.txt", "same.txt", "out/2.txt"];
+
+        let mut output = Vec::new();
+        super::write(&mut output, "mv", true, Forward::new(&from, &to)).unwrap();
+        let expected = "#!/bin/sh\nset -e\nmkdir -p -- 'out'\n\
+            mv -- 'a b.txt' 'out/1.txt'\nmv -- 'c'\\''d.txt' 'out/2.txt'\n";
+        assert_eq!(expected, String::from_utf8(output).unwrap());
+    }
+}
@@ -0,0 +1,62 @@
+use std::{io, path::Path};
+
+/// The extended attribute in which Finder keeps a file's tags, as a binary plist of strings
+#[cfg(target_os = "macos")]
+const TAGS_ATTRIBUTE: &str = "com.apple.metadata:_kMDItemUserTags";
+
+/// Read the Finder tags of a file, without their colour labels
+///
+/// Untagged files have no tags.
+#[cfg(target_os = "macos")]
+pub fn read(path: &Path) -> io::Result<Vec<String>> {
+    let data = match xattr::get(path, TAGS_ATTRIBUTE) {
+        Ok(Some(data)) => data,
+        Ok(None) => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("unable to read tags: {}\n  {}", e, path.display()),
+            ))
+        }
+    };
+
+    let tags: Vec<String> = plist::from_bytes(&data).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unable to read tags: {}\n  {}", e, path.display()),
+        )
+    })?;
+
+    // Tags with a colour are stored as "name\ncolour"
+    Ok(tags
+        .into_iter()
+        .map(|tag| match tag.split_once('\n') {
+            Some((name, _)) => name.into(),
+            None => tag,
+        })
+        .collect())
+}
+
+/// Finder tags exist only on macOS; elsewhere, every file is untagged
+#[cfg(not(target_os = "macos"))]
+pub fn read(_path: &Path) -> io::Result<Vec<String>> {
+    Ok(Vec::new())
+}
+
+#[cfg(all(test, target_os = "macos"))]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn reads_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.jpg");
+        fs::write(&path, "").unwrap();
+        assert!(super::read(&path).unwrap().is_empty());
+
+        let mut data = Vec::new();
+        plist::to_writer_binary(&mut data, &vec!["Red\n6", "Keep"]).unwrap();
+        xattr::set(&path, super::TAGS_ATTRIBUTE, &data).unwrap();
+        assert_eq!(vec!["Red", "Keep"], super::read(&path).unwrap());
+    }
+}
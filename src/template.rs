@@ -1,5 +1,6 @@
-use std::slice;
+use std::{error, fmt, slice};
 
+use chrono::format::{Item, StrftimeItems};
 use regex::{Match, Regex};
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -7,25 +8,158 @@ pub enum Segment {
     /// A literal segment
     Literal(String),
 
-    /// Indicates a numeric segment; the integer indicates the formatting width of the number
-    Numeric(usize),
+    /// Indicates a numeric segment; the integer indicates the formatting width of the number and
+    /// the flag whether its digits are grouped in threes
+    Numeric(usize, bool),
 
-    /// Segment indicating use of the original filename; integer indicates how much of the filename to use
-    Filename(usize),
+    /// A numeric segment counting from one less than the start value; fields as for `Numeric`
+    ZeroBased(usize, bool),
+
+    /// Segment indicating use of the original filename, or as much of it as the length allows
+    ///
+    /// When a pattern is given, this is the part of the filename the pattern selected.
+    Filename(Length),
+
+    /// The original file stem, ignoring any pattern, or as much of it as the length allows
+    RawFilename(Length),
+
+    /// A number captured from the original filename, zero-padded to the given width
+    CapturedNumber(usize),
+
+    /// The text of a named or numbered capture group from the pattern
+    Group(String),
+
+    /// The number of lines in the original file
+    LineCount,
+
+    /// The time at which the batch was started, rendered with a strftime-style format string
+    Now(String),
+
+    /// The time at which the original file was last modified, rendered as for `Now`
+    Modified(String),
+
+    /// Milliseconds since the Unix epoch at render time, increased as needed to stay unique
+    Timestamp,
+
+    /// How many directories deep the file was found below a directory argument
+    Depth,
+
+    /// The file's directory relative to its input, with separators replaced
+    ParentPath,
+
+    /// A field of the original file's ID3 tag
+    Tag(TagField),
+}
+
+/// A field of an ID3 tag, e.g. {artist}
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TagField {
+    Artist,
+    Album,
+    Title,
+    Track,
+}
+
+impl TagField {
+    pub fn name(self) -> &'static str {
+        match self {
+            TagField::Artist => "artist",
+            TagField::Album => "album",
+            TagField::Title => "title",
+            TagField::Track => "track",
+        }
+    }
 }
 
+/// How much of a filename to insert
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Length {
+    /// The first N characters, e.g. {o:3}; a length of one inserts the whole name
+    Chars(usize),
+
+    /// The first N whitespace-delimited words, e.g. {o:w3}, or the last N if negative, e.g. {o:w-2}
+    Words(isize),
+}
+
+#[derive(Clone, Debug)]
+pub enum TemplateError {
+    InvalidDateFormat(String),
+    UnknownGroup {
+        name: String,
+        available: Vec<String>,
+    },
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::InvalidDateFormat(format) => {
+                write!(f, "invalid date format: {}", format)
+            }
+            TemplateError::UnknownGroup { name, available } if available.is_empty() => {
+                write!(
+                    f,
+                    "unknown capture group: {} (the pattern has no groups)",
+                    name
+                )
+            }
+            TemplateError::UnknownGroup { name, available } => write!(
+                f,
+                "unknown capture group: {} (available: {})",
+                name,
+                available.join(", ")
+            ),
+        }
+    }
+}
+
+impl error::Error for TemplateError {}
+
 pub struct TemplateParser {
     pattern: Regex,
+    format: Regex,
 }
 
 impl TemplateParser {
     pub fn new() -> Self {
         Self {
-            pattern: Regex::new(r#"[^\\]?(\{([FfNnOo0])(:\d+)?\})"#).unwrap(),
+            pattern: Regex::new(
+                r#"[^\\]?(\{(?:([FfOo]|cn)(:w-?\d+|:\d+)?|([Nn0Zz])(:\d+)?(:group)?|(now|m)(:[^}]+)|(g)(:\w+)|(lines|ts|depth|pp|artist|album|title|track))\})"#,
+            )
+            .unwrap(),
+            format: Regex::new(r"\{(\d+|n)\}").unwrap(),
+        }
+    }
+
+    /// Parse a --format string, in which {1}..{N} are capture groups and {n} is the index
+    ///
+    /// Nothing else is special, so text which would otherwise be a token is left as it is.
+    pub fn parse_format(&self, format: &str) -> Template {
+        let mut segments = Vec::new();
+        let mut left = 0;
+
+        for cx in self.format.captures_iter(format) {
+            let token = cx.get(0).unwrap();
+            if token.start() > left {
+                segments.push(Segment::Literal(format[left..token.start()].into()));
+            }
+
+            match &cx[1] {
+                "n" => segments.push(Segment::Numeric(1, false)),
+                group => segments.push(Segment::Group(group.into())),
+            }
+
+            left = token.end();
+        }
+
+        if left < format.len() {
+            segments.push(Segment::Literal(format[left..].into()));
         }
+
+        Template { segments }
     }
 
-    pub fn parse(&self, template: &str) -> Template {
+    pub fn parse(&self, template: &str) -> Result<Template, TemplateError> {
         let captures = self.pattern.captures_iter(template);
 
         let mut segments = Vec::new();
@@ -34,8 +168,20 @@ impl TemplateParser {
         let captures = captures.filter_map(|cx| {
             Some(Formatter {
                 template: cx.get(1)?,
-                specifier: cx.get(2)?.as_str(),
-                quantifier: cx.get(3).map(|cx| cx.as_str()),
+                specifier: cx
+                    .get(2)
+                    .or_else(|| cx.get(4))
+                    .or_else(|| cx.get(7))
+                    .or_else(|| cx.get(9))
+                    .or_else(|| cx.get(11))?
+                    .as_str(),
+                quantifier: cx
+                    .get(3)
+                    .or_else(|| cx.get(5))
+                    .or_else(|| cx.get(8))
+                    .or_else(|| cx.get(10))
+                    .map(|cx| cx.as_str()),
+                grouped: cx.get(6).is_some(),
             })
         });
 
@@ -47,8 +193,29 @@ impl TemplateParser {
             }
 
             match formatter.specifier {
-                "0" | "n" | "N" => segments.push(Segment::Numeric(formatter.quantifier())),
-                "o" | "O" | "f" | "F" => segments.push(Segment::Filename(formatter.quantifier())),
+                "0" | "n" | "N" => {
+                    segments.push(Segment::Numeric(formatter.quantifier(), formatter.grouped))
+                }
+                "z" | "Z" => segments.push(Segment::ZeroBased(
+                    formatter.quantifier(),
+                    formatter.grouped,
+                )),
+                "cn" => segments.push(Segment::CapturedNumber(formatter.quantifier())),
+                "o" | "f" => segments.push(Segment::Filename(formatter.length())),
+                "O" | "F" => segments.push(Segment::RawFilename(formatter.length())),
+                "now" => segments.push(Segment::Now(validate_date_format(formatter.argument())?)),
+                "m" => segments.push(Segment::Modified(validate_date_format(
+                    formatter.argument(),
+                )?)),
+                "g" => segments.push(Segment::Group(formatter.argument().into())),
+                "lines" => segments.push(Segment::LineCount),
+                "ts" => segments.push(Segment::Timestamp),
+                "depth" => segments.push(Segment::Depth),
+                "pp" => segments.push(Segment::ParentPath),
+                "artist" => segments.push(Segment::Tag(TagField::Artist)),
+                "album" => segments.push(Segment::Tag(TagField::Album)),
+                "title" => segments.push(Segment::Tag(TagField::Title)),
+                "track" => segments.push(Segment::Tag(TagField::Track)),
                 _ => (),
             }
 
@@ -59,14 +226,47 @@ impl TemplateParser {
             segments.push(Segment::Literal(template[left..].into()));
         }
 
-        Template { segments }
+        Ok(Template { segments })
+    }
+}
+
+/// Ensure that a capture group, given by name or number, exists in `pattern`
+pub fn check_group(pattern: Option<&Regex>, name: &str) -> Result<(), TemplateError> {
+    let exists = match (pattern, name.parse::<usize>()) {
+        (Some(pattern), Ok(idx)) => idx < pattern.captures_len(),
+        (Some(pattern), Err(_)) => pattern.capture_names().flatten().any(|x| x == name),
+        (None, _) => false,
+    };
+
+    if !exists {
+        let available = pattern
+            .map(|pattern| {
+                let named = pattern.capture_names().flatten().map(String::from);
+                let numbered = (1..pattern.captures_len()).map(|idx| idx.to_string());
+                named.chain(numbered).collect()
+            })
+            .unwrap_or_default();
+
+        return Err(TemplateError::UnknownGroup {
+            name: name.into(),
+            available,
+        });
     }
+    Ok(())
+}
+
+fn validate_date_format(format: &str) -> Result<String, TemplateError> {
+    if StrftimeItems::new(format).any(|item| item == Item::Error) {
+        return Err(TemplateError::InvalidDateFormat(format.into()));
+    }
+    Ok(format.into())
 }
 
 struct Formatter<'a> {
     template: Match<'a>,
     specifier: &'a str,
     quantifier: Option<&'a str>,
+    grouped: bool,
 }
 
 impl Formatter<'_> {
@@ -78,6 +278,18 @@ impl Formatter<'_> {
             })
             .unwrap_or(1)
     }
+
+    /// The quantifier of a filename token, which counts words when prefixed with `w`
+    fn length(&self) -> Length {
+        match self.argument().strip_prefix('w') {
+            Some(words) => Length::Words(words.parse().unwrap_or(1)),
+            None => Length::Chars(self.quantifier()),
+        }
+    }
+
+    fn argument(&self) -> &str {
+        self.quantifier.map(|s| &s[1..]).unwrap_or_default()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -86,25 +298,205 @@ pub struct Template {
 }
 
 impl Template {
-    pub fn segments(&self) -> slice::Iter<Segment> {
+    pub fn segments(&self) -> slice::Iter<'_, Segment> {
         self.segments.iter()
     }
+
+    /// Ensure that every capture group referenced by the template exists in `pattern`
+    pub fn check_groups(&self, pattern: Option<&Regex>) -> Result<(), TemplateError> {
+        for segment in &self.segments {
+            if let Segment::Group(name) = segment {
+                check_group(pattern, name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A regular expression matching names rendered by this template, capturing the first number
+    ///
+    /// Returns `None` if the template has no sequence number. Other tokens match any text.
+    pub fn numeric_pattern(&self) -> Option<String> {
+        let mut pattern = String::new();
+        let mut found = false;
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => pattern += &regex::escape(text),
+                Segment::Numeric(_, false) if !found => {
+                    pattern += r"(\d+)";
+                    found = true;
+                }
+                _ => pattern += ".*?",
+            }
+        }
+
+        found.then_some(pattern)
+    }
+
+    /// True if rendering this template stamps each name with the current time
+    pub fn uses_timestamp(&self) -> bool {
+        self.segments.contains(&Segment::Timestamp)
+    }
+
+    /// True if rendering this template requires reading the contents of the original file
+    pub fn uses_line_count(&self) -> bool {
+        self.segments.contains(&Segment::LineCount)
+    }
+
+    /// True if rendering this template requires the depth of the file below its input directory
+    pub fn uses_depth(&self) -> bool {
+        self.segments.contains(&Segment::Depth)
+    }
+
+    /// True if rendering this template requires the directory of the file relative to its input
+    pub fn uses_parent_path(&self) -> bool {
+        self.segments.contains(&Segment::ParentPath)
+    }
+
+    /// True if rendering this template requires the time at which the original file was modified
+    pub fn uses_modified(&self) -> bool {
+        self.segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Modified(_)))
+    }
+
+    /// True if rendering this template requires reading the file's ID3 tag
+    pub fn uses_tags(&self) -> bool {
+        self.segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Tag(_)))
+    }
+
+    /// True if rendering this template requires the original filename
+    pub fn uses_name(&self) -> bool {
+        self.segments.iter().any(|segment| {
+            matches!(
+                segment,
+                Segment::Filename(_)
+                    | Segment::RawFilename(_)
+                    | Segment::CapturedNumber(_)
+                    | Segment::Group(_)
+            )
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::template::{Template, TemplateParser};
+    use regex::Regex;
+
+    use crate::template::{Length, TagField, Template, TemplateParser};
 
     #[test]
     fn can_create_template() {
         let parser = TemplateParser::new();
-        let Template { segments } = parser.parse("Moab Vacation {o} {n:4}");
+        let Template { segments } = parser.parse("Moab Vacation {o} {n:4}").unwrap();
         let expected = vec![
             super::Segment::Literal(String::from("Moab Vacation ")),
-            super::Segment::Filename(1),
+            super::Segment::Filename(Length::Chars(1)),
+            super::Segment::Literal(String::from(" ")),
+            super::Segment::Numeric(4, false),
+        ];
+        assert_eq!(segments, expected);
+    }
+
+    #[test]
+    fn can_parse_word_lengths() {
+        let parser = TemplateParser::new();
+        let Template { segments } = parser.parse("{o:w3}-{O:w-2}").unwrap();
+        let expected = vec![
+            super::Segment::Filename(Length::Words(3)),
+            super::Segment::Literal(String::from("-")),
+            super::Segment::RawFilename(Length::Words(-2)),
+        ];
+        assert_eq!(segments, expected);
+    }
+
+    #[test]
+    fn can_parse_tags() {
+        let parser = TemplateParser::new();
+        let Template { segments } = parser.parse("{track} {artist} - {title}").unwrap();
+        let expected = vec![
+            super::Segment::Tag(TagField::Track),
+            super::Segment::Literal(String::from(" ")),
+            super::Segment::Tag(TagField::Artist),
+            super::Segment::Literal(String::from(" - ")),
+            super::Segment::Tag(TagField::Title),
+        ];
+        assert_eq!(segments, expected);
+    }
+
+    #[test]
+    fn can_parse_now() {
+        let parser = TemplateParser::new();
+        let Template { segments } = parser.parse("backup-{now:%Y%m%d}-{n:3}").unwrap();
+        let expected = vec![
+            super::Segment::Literal(String::from("backup-")),
+            super::Segment::Now(String::from("%Y%m%d")),
+            super::Segment::Literal(String::from("-")),
+            super::Segment::Numeric(3, false),
+        ];
+        assert_eq!(segments, expected);
+    }
+
+    #[test]
+    fn groups_must_exist() {
+        let parser = TemplateParser::new();
+        let template = parser.parse("{g:show} {g:1}").unwrap();
+        let pattern = Regex::new(r"(?P<show>.+) S\d+").unwrap();
+        assert!(template.check_groups(Some(&pattern)).is_ok());
+
+        let template = parser.parse("{g:season}").unwrap();
+        let error = template.check_groups(Some(&pattern)).unwrap_err();
+        assert_eq!(
+            "unknown capture group: season (available: show, 1)",
+            error.to_string()
+        );
+        assert!(template.check_groups(None).is_err());
+    }
+
+    #[test]
+    fn can_parse_grouped_numbers() {
+        let parser = TemplateParser::new();
+        let Template { segments } = parser.parse("{n:group} {z:6:group} {o:group}").unwrap();
+        let expected = vec![
+            super::Segment::Numeric(1, true),
+            super::Segment::Literal(String::from(" ")),
+            super::Segment::ZeroBased(6, true),
+            super::Segment::Literal(String::from(" {o:group}")),
+        ];
+        assert_eq!(segments, expected);
+    }
+
+    #[test]
+    fn can_parse_format() {
+        let parser = TemplateParser::new();
+        let Template { segments } = parser.parse_format("{2} - {1} {n} {o}");
+        let expected = vec![
+            super::Segment::Group(String::from("2")),
+            super::Segment::Literal(String::from(" - ")),
+            super::Segment::Group(String::from("1")),
             super::Segment::Literal(String::from(" ")),
-            super::Segment::Numeric(4),
+            super::Segment::Numeric(1, false),
+            super::Segment::Literal(String::from(" {o}")),
         ];
         assert_eq!(segments, expected);
     }
+
+    #[test]
+    fn numeric_pattern() {
+        let parser = TemplateParser::new();
+        let template = parser.parse("img (1) {n:3}-{o}").unwrap();
+        assert_eq!(
+            Some(String::from(r"img \(1\) (\d+)\-.*?")),
+            template.numeric_pattern()
+        );
+        assert_eq!(None, parser.parse("{o}").unwrap().numeric_pattern());
+    }
+
+    #[test]
+    fn invalid_date_format_is_rejected() {
+        let parser = TemplateParser::new();
+        assert!(parser.parse("backup-{now:%Q}").is_err());
+    }
 }